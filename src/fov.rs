@@ -0,0 +1,310 @@
+use crate::shadowcast::*;
+use coord_2d::Coord;
+use direction::DirectionBitmap;
+use std::cmp;
+use std::convert::Infallible;
+use std::mem;
+use std::ops::ControlFlow;
+
+/// How the view of a cell changed when a `Fov` was updated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FovChange<Visibility> {
+    /// The cell was not visible before
+    Revealed(DirectionBitmap, Visibility),
+    /// The cell is still visible, but different edges or a different amount of it can be
+    /// seen
+    Changed(DirectionBitmap, Visibility),
+    /// The cell is no longer visible
+    Hidden,
+}
+
+/// The cells visible from a view, kept so that it can be updated when the grid changes
+/// without observing everything again
+#[derive(Debug, Clone)]
+pub struct Fov<Visibility, L = NoFalloff> {
+    view: Option<View<L>>,
+    /// sorted by coord, with each coord appearing once
+    cells: Vec<(Coord, DirectionBitmap, Visibility)>,
+    scan: Vec<(Coord, DirectionBitmap, Visibility)>,
+    next: Vec<(Coord, DirectionBitmap, Visibility)>,
+}
+
+impl<Visibility, L> Default for Fov<Visibility, L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Visibility, L> Fov<Visibility, L> {
+    pub fn new() -> Self {
+        Self {
+            view: None,
+            cells: Vec::new(),
+            scan: Vec::new(),
+            next: Vec::new(),
+        }
+    }
+
+    /// The cell the view was observed from, or `None` if nothing has been observed yet
+    pub fn eye(&self) -> Option<Coord> {
+        self.view.as_ref().map(View::coord)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// The visible edges of a cell and how much of it is visible, or `None` if it isn't
+    /// visible
+    pub fn get(&self, coord: Coord) -> Option<(DirectionBitmap, Visibility)>
+    where
+        Visibility: Copy,
+    {
+        self.cells
+            .binary_search_by_key(&coord, |&(coord, _, _)| coord)
+            .ok()
+            .map(|index| {
+                let (_, bitmap, visibility) = self.cells[index];
+                (bitmap, visibility)
+            })
+    }
+
+    /// The visible cells, in order of coordinate
+    pub fn iter(&self) -> impl '_ + Iterator<Item = (Coord, DirectionBitmap, Visibility)>
+    where
+        Visibility: Copy,
+    {
+        self.cells.iter().cloned()
+    }
+
+    /// Replace the visible cells within the box from `min` to `max` for which `affected`
+    /// is true with those in `self.next`, reporting the differences
+    fn replace<A, F>(&mut self, (min, max): (Coord, Coord), mut affected: A, f: &mut F)
+    where
+        Visibility: Copy + PartialEq,
+        A: FnMut(Coord) -> bool,
+        F: FnMut(Coord, FovChange<Visibility>),
+    {
+        // cells are sorted by x coordinate first, so the cells in columns outside the box
+        // can be kept without looking at them
+        let columns = |cells: &[(Coord, DirectionBitmap, Visibility)]| {
+            cells.partition_point(|&(coord, _, _)| coord.x < min.x)
+                ..cells.partition_point(|&(coord, _, _)| coord.x <= max.x)
+        };
+        let prev_columns = columns(&self.cells);
+        let next_columns = columns(&self.next);
+        let mut cells = mem::take(&mut self.scan);
+        cells.clear();
+        cells.extend_from_slice(&self.cells[..prev_columns.start]);
+        let mut prev_cells = self.cells[prev_columns.clone()].iter().copied().peekable();
+        let mut next_cells = self.next[next_columns].iter().copied().peekable();
+        loop {
+            let coord = match (prev_cells.peek(), next_cells.peek()) {
+                (Some(&(a, _, _)), Some(&(b, _, _))) => cmp::min(a, b),
+                (Some(&(coord, _, _)), None) | (None, Some(&(coord, _, _))) => coord,
+                (None, None) => break,
+            };
+            let prev = prev_cells.next_if(|&(prev_coord, _, _)| prev_coord == coord);
+            let next = next_cells.next_if(|&(next_coord, _, _)| next_coord == coord);
+            if coord.y < min.y || coord.y > max.y || !affected(coord) {
+                cells.extend(prev);
+                continue;
+            }
+            match (prev, next) {
+                (None, Some((_, bitmap, visibility))) => {
+                    f(coord, FovChange::Revealed(bitmap, visibility));
+                }
+                (Some(prev), Some((_, bitmap, visibility)))
+                    if prev != (coord, bitmap, visibility) =>
+                {
+                    f(coord, FovChange::Changed(bitmap, visibility));
+                }
+                (Some(_), None) => f(coord, FovChange::Hidden),
+                _ => (),
+            }
+            cells.extend(next);
+        }
+        cells.extend_from_slice(&self.cells[prev_columns.end..]);
+        self.next.clear();
+        self.scan = mem::replace(&mut self.cells, cells);
+    }
+}
+
+impl<Visibility> Context<Visibility> {
+    /// Observe everything visible from `view`, storing it in `fov` and reporting how it
+    /// differs from what was previously stored there
+    #[allow(clippy::too_many_arguments)]
+    pub fn observe_fov<W, L, I, V, F>(
+        &mut self,
+        fov: &mut Fov<Visibility, L>,
+        view: W,
+        input_grid: &I,
+        grid: &I::Grid,
+        vision_distance: V,
+        initial_visibility: Visibility,
+        mut f: F,
+    ) where
+        W: Into<View<L>>,
+        L: ApplyFalloff<Visibility> + Sync,
+        I: InputGrid,
+        V: VisionDistance,
+        F: FnMut(Coord, FovChange<Visibility>),
+        Visibility: Attenuate<I::Opacity>,
+    {
+        self.observe_fov_with_coverage(
+            fov,
+            view.into(),
+            Coverage::All,
+            input_grid,
+            grid,
+            vision_distance,
+            initial_visibility,
+        );
+        fov.replace(
+            (
+                Coord::new(i32::MIN, i32::MIN),
+                Coord::new(i32::MAX, i32::MAX),
+            ),
+            |_| true,
+            &mut f,
+        );
+    }
+
+    /// Update `fov` after the opacity of the cell at `changed` has changed, reporting the
+    /// cells whose view changed as a result. Only the wedge of cells which can be affected
+    /// by the change is scanned again. `fov` must have been observed with the same mode and
+    /// vision distance. Permissive modes, wrapping grids, and views whose eye isn't in the
+    /// centre of a single cell or which take the heights of cells into account aren't
+    /// handled incrementally, so everything is observed again instead.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_fov_cell<L, I, V, F>(
+        &mut self,
+        fov: &mut Fov<Visibility, L>,
+        changed: Coord,
+        input_grid: &I,
+        grid: &I::Grid,
+        vision_distance: V,
+        initial_visibility: Visibility,
+        mut f: F,
+    ) where
+        L: ApplyFalloff<Visibility> + Sync,
+        I: InputGrid,
+        V: VisionDistance,
+        F: FnMut(Coord, FovChange<Visibility>),
+        Visibility: Attenuate<I::Opacity>,
+    {
+        let view = match fov.view {
+            Some(view) => view,
+            None => return,
+        };
+        let eye = view.coord();
+        let unbounded = input_grid.is_unbounded(grid);
+        if matches!(self.mode(), Mode::Permissive(1..))
+            || (!unbounded && input_grid.wrap(grid) != Wrap::None)
+            || !view.is_centred_in_cell()
+        {
+            return self.observe_fov(
+                fov,
+                view,
+                input_grid,
+                grid,
+                vision_distance,
+                initial_visibility,
+                f,
+            );
+        }
+        let changed = changed - eye;
+        // The eye's own cell never obstructs the view, and cells beyond the furthest depth
+        // which is scanned (other than those just beyond the edges of octants) are never
+        // looked at. That depth is also bounded by the size of the grid.
+        let mut max_depth = grid_max_depth(input_grid, grid, vision_distance);
+        if !unbounded {
+            let size = input_grid.size(grid);
+            max_depth = cmp::min(max_depth, cmp::max(size.width(), size.height()) as i32);
+        }
+        let changed_depth = cmp::max(changed.x.unsigned_abs(), changed.y.unsigned_abs());
+        if changed == Coord::new(0, 0) || changed_depth as i64 > max_depth as i64 + 1 {
+            return;
+        }
+        // A cell along a diagonal is seen through two octants. When the changed cell is
+        // close to the eye, the region scanned in one of them can miss lines of sight to
+        // such a cell which the other octant sees, and most of the view can be affected
+        // anyway, so everything is observed again.
+        if changed_depth as i64 <= REGION_MARGIN {
+            return self.observe_fov(
+                fov,
+                view,
+                input_grid,
+                grid,
+                vision_distance,
+                initial_visibility,
+                f,
+            );
+        }
+        let (min, max) = match region_bounds(changed, max_depth) {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        self.observe_fov_with_coverage(
+            fov,
+            view,
+            Coverage::Region(changed),
+            input_grid,
+            grid,
+            vision_distance,
+            initial_visibility,
+        );
+        let absolute = |delta: Coord| {
+            let clamp = |eye: i32, delta: i32| {
+                (eye as i64 + delta as i64).clamp(i32::MIN as i64, i32::MAX as i64) as i32
+            };
+            Coord::new(clamp(eye.x, delta.x), clamp(eye.y, delta.y))
+        };
+        fov.replace(
+            (absolute(min), absolute(max)),
+            |coord| region_effect(changed, coord - eye),
+            &mut f,
+        );
+    }
+
+    /// Observe the cells within `coverage` into `fov.next`, without any duplicates
+    #[allow(clippy::too_many_arguments)]
+    fn observe_fov_with_coverage<L, I, V>(
+        &mut self,
+        fov: &mut Fov<Visibility, L>,
+        view: View<L>,
+        coverage: Coverage,
+        input_grid: &I,
+        grid: &I::Grid,
+        vision_distance: V,
+        initial_visibility: Visibility,
+    ) where
+        L: ApplyFalloff<Visibility> + Sync,
+        I: InputGrid,
+        V: VisionDistance,
+        Visibility: Attenuate<I::Opacity>,
+    {
+        fov.view = Some(view);
+        let Fov { scan, next, .. } = fov;
+        scan.clear();
+        next.clear();
+        self.observe(
+            view,
+            coverage,
+            input_grid,
+            grid,
+            vision_distance,
+            initial_visibility,
+            |coord, bitmap, visibility| scan.push((coord, bitmap, visibility)),
+        );
+        let _: ControlFlow<Infallible> =
+            report_merged(scan, &mut |coord, bitmap, visibility| {
+                next.push((coord, bitmap, visibility));
+                ControlFlow::Continue(())
+            });
+    }
+}
@@ -1,6 +1,12 @@
+mod fov;
 mod octants;
+#[cfg(feature = "rayon")]
+mod parallel;
 mod shadowcast;
 
+pub use crate::fov::*;
+#[cfg(feature = "rayon")]
+pub use crate::parallel::*;
 pub use crate::shadowcast::*;
 
 #[cfg(test)]
@@ -1,48 +1,42 @@
 use coord_2d::Coord;
-use direction::{Direction, DirectionBitmap};
+use direction::{CardinalDirection, Direction, DirectionBitmap};
 
 pub trait Octant {
-    fn depth_index(&self, centre: Coord, depth: i32) -> Option<i32>;
-    fn make_coord(&self, centre: Coord, lateral_offset: i32, depth_index: i32) -> Coord;
-    fn lateral_max(&self, centre: Coord) -> i32;
     fn facing_bitmap(&self) -> DirectionBitmap;
     fn across_bitmap(&self) -> DirectionBitmap;
     fn facing_corner_bitmap(&self) -> DirectionBitmap;
     fn should_see(&self, lateral_offset: i32) -> bool;
+    fn depth_direction(&self) -> CardinalDirection;
+    fn lateral_direction(&self) -> CardinalDirection;
+
+    /// The cell at the given lateral offset and depth from the centre
+    fn make_coord(&self, centre: Coord, lateral_offset: i32, depth: i32) -> Coord {
+        let lateral = self.lateral_direction().coord();
+        let depth_unit = self.depth_direction().coord();
+        Coord::new(
+            centre.x + lateral.x * lateral_offset + depth_unit.x * depth,
+            centre.y + lateral.y * lateral_offset + depth_unit.y * depth,
+        )
+    }
+
+    /// The lateral offset and depth of a cell, relative to the centre, within this octant
+    fn lateral_and_depth(&self, delta: Coord) -> (i32, i32) {
+        let dot = |direction: CardinalDirection| {
+            let unit = direction.coord();
+            delta.x * unit.x + delta.y * unit.y
+        };
+        (dot(self.lateral_direction()), dot(self.depth_direction()))
+    }
 }
 
 pub struct TopLeft;
 pub struct LeftTop;
-pub struct TopRight {
-    pub width: i32,
-}
-pub struct RightTop {
-    pub width: i32,
-}
-pub struct BottomLeft {
-    pub height: i32,
-}
-pub struct LeftBottom {
-    pub height: i32,
-}
-pub struct BottomRight {
-    pub width: i32,
-    pub height: i32,
-}
-pub struct RightBottom {
-    pub width: i32,
-    pub height: i32,
-}
-
-macro_rules! some_if {
-    ($value:expr, $condition:expr) => {
-        if $condition {
-            Some($value)
-        } else {
-            None
-        }
-    };
-}
+pub struct TopRight;
+pub struct RightTop;
+pub struct BottomLeft;
+pub struct LeftBottom;
+pub struct BottomRight;
+pub struct RightBottom;
 
 macro_rules! see_ahead {
     () => {
@@ -72,6 +66,17 @@ macro_rules! across {
     }
 }
 
+macro_rules! axes {
+    ($depth:expr, $lateral:expr) => {
+        fn depth_direction(&self) -> CardinalDirection {
+            $depth
+        }
+        fn lateral_direction(&self) -> CardinalDirection {
+            $lateral
+        }
+    }
+}
+
 macro_rules! facing_corner {
     ($dirs:expr) => {
         fn facing_corner_bitmap(&self) -> DirectionBitmap {
@@ -81,137 +86,65 @@ macro_rules! facing_corner {
 }
 
 impl Octant for TopRight {
-    fn depth_index(&self, centre: Coord, depth: i32) -> Option<i32> {
-        let index = centre.y - depth;
-        some_if!(index, index >= 0)
-    }
-    fn make_coord(&self, centre: Coord, lateral_offset: i32, depth_index: i32) -> Coord {
-        Coord::new(centre.x + lateral_offset, depth_index)
-    }
-    fn lateral_max(&self, centre: Coord) -> i32 {
-        self.width - centre.x - 1
-    }
     facing!{Direction::South.bitmap()}
     across!{Direction::West.bitmap()}
     facing_corner!{Direction::SouthWest.bitmap()}
     see_ahead!{}
+    axes!{CardinalDirection::North, CardinalDirection::East}
 }
 
 impl Octant for RightTop {
-    fn depth_index(&self, centre: Coord, depth: i32) -> Option<i32> {
-        let index = centre.x + depth;
-        some_if!(index, index < self.width)
-    }
-    fn make_coord(&self, centre: Coord, lateral_offset: i32, depth_index: i32) -> Coord {
-        Coord::new(depth_index, centre.y - lateral_offset)
-    }
-    fn lateral_max(&self, centre: Coord) -> i32 {
-        centre.y
-    }
     facing!{Direction::West.bitmap()}
     across!{Direction::South.bitmap()}
     facing_corner!{Direction::SouthWest.bitmap()}
     no_see_ahead!{}
+    axes!{CardinalDirection::East, CardinalDirection::North}
 }
 
 impl Octant for TopLeft {
-    fn depth_index(&self, centre: Coord, depth: i32) -> Option<i32> {
-        let index = centre.y - depth;
-        some_if!(index, index >= 0)
-    }
-    fn make_coord(&self, centre: Coord, lateral_offset: i32, depth_index: i32) -> Coord {
-        Coord::new(centre.x - lateral_offset, depth_index)
-    }
-    fn lateral_max(&self, centre: Coord) -> i32 {
-        centre.x
-    }
     facing!{Direction::South.bitmap()}
     across!{Direction::East.bitmap()}
     facing_corner!{Direction::SouthEast.bitmap()}
     no_see_ahead!{}
+    axes!{CardinalDirection::North, CardinalDirection::West}
 }
 
 impl Octant for LeftTop {
-    fn depth_index(&self, centre: Coord, depth: i32) -> Option<i32> {
-        let index = centre.x - depth;
-        some_if!(index, index >= 0)
-    }
-    fn make_coord(&self, centre: Coord, lateral_offset: i32, depth_index: i32) -> Coord {
-        Coord::new(depth_index, centre.y - lateral_offset)
-    }
-    fn lateral_max(&self, centre: Coord) -> i32 {
-        centre.y
-    }
     facing!{Direction::East.bitmap()}
     across!{Direction::South.bitmap()}
     facing_corner!{Direction::SouthEast.bitmap()}
     see_ahead!{}
+    axes!{CardinalDirection::West, CardinalDirection::North}
 }
 
 impl Octant for BottomLeft {
-    fn depth_index(&self, centre: Coord, depth: i32) -> Option<i32> {
-        let index = centre.y + depth;
-        some_if!(index, index < self.height)
-    }
-    fn make_coord(&self, centre: Coord, lateral_offset: i32, depth_index: i32) -> Coord {
-        Coord::new(centre.x - lateral_offset, depth_index)
-    }
-    fn lateral_max(&self, centre: Coord) -> i32 {
-        centre.x
-    }
     facing!{Direction::North.bitmap()}
     across!{Direction::East.bitmap()}
     facing_corner!{Direction::NorthEast.bitmap()}
     see_ahead!{}
+    axes!{CardinalDirection::South, CardinalDirection::West}
 }
 
 impl Octant for LeftBottom {
-    fn depth_index(&self, centre: Coord, depth: i32) -> Option<i32> {
-        let index = centre.x - depth;
-        some_if!(index, index >= 0)
-    }
-    fn make_coord(&self, centre: Coord, lateral_offset: i32, depth_index: i32) -> Coord {
-        Coord::new(depth_index, centre.y + lateral_offset)
-    }
-    fn lateral_max(&self, centre: Coord) -> i32 {
-        self.height - centre.y - 1
-    }
     facing!{Direction::East.bitmap()}
     across!{Direction::North.bitmap()}
     facing_corner!{Direction::NorthEast.bitmap()}
     no_see_ahead!{}
+    axes!{CardinalDirection::West, CardinalDirection::South}
 }
 
 impl Octant for BottomRight {
-    fn depth_index(&self, centre: Coord, depth: i32) -> Option<i32> {
-        let index = centre.y + depth;
-        some_if!(index, index < self.height)
-    }
-    fn make_coord(&self, centre: Coord, lateral_offset: i32, depth_index: i32) -> Coord {
-        Coord::new(centre.x + lateral_offset, depth_index)
-    }
-    fn lateral_max(&self, centre: Coord) -> i32 {
-        self.width - centre.x - 1
-    }
     facing!{Direction::North.bitmap()}
     across!{Direction::West.bitmap()}
     facing_corner!{Direction::NorthWest.bitmap()}
     no_see_ahead!{}
+    axes!{CardinalDirection::South, CardinalDirection::East}
 }
 
 impl Octant for RightBottom {
-    fn depth_index(&self, centre: Coord, depth: i32) -> Option<i32> {
-        let index = centre.x + depth;
-        some_if!(index, index < self.width)
-    }
-    fn make_coord(&self, centre: Coord, lateral_offset: i32, depth_index: i32) -> Coord {
-        Coord::new(depth_index, centre.y + lateral_offset)
-    }
-    fn lateral_max(&self, centre: Coord) -> i32 {
-        self.height - centre.y - 1
-    }
     facing!{Direction::West.bitmap()}
     across!{Direction::North.bitmap()}
     facing_corner!{Direction::NorthWest.bitmap()}
     see_ahead!{}
+    axes!{CardinalDirection::East, CardinalDirection::South}
 }
@@ -0,0 +1,258 @@
+use crate::octants::*;
+use crate::shadowcast::*;
+use coord_2d::Coord;
+use direction::DirectionBitmap;
+use rayon::prelude::*;
+use std::convert::Infallible;
+use std::mem;
+use std::ops::ControlFlow;
+use std::sync::{Mutex, MutexGuard};
+
+/// Scratch space for scanning a pair of octants on its own thread
+#[derive(Clone, Debug)]
+pub(crate) struct OctantPair<Visibility> {
+    context: Context<Visibility>,
+    output: Vec<(Coord, DirectionBitmap, Visibility)>,
+}
+
+impl<Visibility> Default for OctantPair<Visibility> {
+    fn default() -> Self {
+        Self {
+            context: Context::default(),
+            output: Vec::new(),
+        }
+    }
+}
+
+/// Scan the pairs of octants concurrently, then report the cells seen by each pair in
+/// the same order as `Sequential`
+pub(crate) struct Parallel;
+
+impl<I, V, Visibility> ObserveOctants<I, V, Visibility> for Parallel
+where
+    I: InputGrid + Sync,
+    I::Grid: Sync,
+    V: VisionDistance + Sync,
+    Visibility: Attenuate<I::Opacity> + Send + Sync,
+{
+    fn observe_octants<F, R>(
+        &self,
+        context: &mut Context<Visibility>,
+        params: &StaticParams<I, Visibility, V>,
+        f: &mut F,
+    ) -> ControlFlow<R>
+    where
+        F: FnMut(Coord, DirectionBitmap, Visibility) -> ControlFlow<R>,
+    {
+        let mut octant_pairs = mem::take(&mut context.octant_pairs);
+        octant_pairs.resize_with(4, Default::default);
+        octant_pairs.par_iter_mut().enumerate().for_each(
+            |(index, OctantPair { context, output })| {
+                let mut f = |coord, bitmap, visibility| {
+                    output.push((coord, bitmap, visibility));
+                    ControlFlow::<Infallible>::Continue(())
+                };
+                let _ = match index {
+                    0 => context.observe_octant(TopLeft, LeftTop, params, &mut f),
+                    1 => context.observe_octant(RightTop, TopRight, params, &mut f),
+                    2 => context.observe_octant(LeftBottom, BottomLeft, params, &mut f),
+                    _ => context.observe_octant(BottomRight, RightBottom, params, &mut f),
+                };
+            },
+        );
+        // The cells seen by every pair are reported, or discarded if `f` breaks, so the
+        // outputs are empty for the next scan.
+        let flow =
+            octant_pairs
+                .iter_mut()
+                .try_for_each(|OctantPair { output, .. }| {
+                    output
+                        .drain(..)
+                        .try_for_each(|(coord, bitmap, visibility)| {
+                            f(coord, bitmap, visibility)
+                        })
+                });
+        for OctantPair {
+            context: pair,
+            output,
+        } in octant_pairs.iter_mut()
+        {
+            output.clear();
+            context.walls.append(&mut pair.walls);
+        }
+        context.octant_pairs = octant_pairs;
+        flow
+    }
+}
+
+impl<Visibility> Context<Visibility> {
+    /// Like `for_each_visible`, but each pair of octants is scanned on its own thread from
+    /// the rayon thread pool. Cells are still reported on the calling thread, in the same
+    /// order as by `for_each_visible`.
+    pub fn par_for_each_visible<W, L, I, V, F>(
+        &mut self,
+        view: W,
+        input_grid: &I,
+        grid: &I::Grid,
+        vision_distance: V,
+        initial_visibility: Visibility,
+        mut f: F,
+    ) where
+        W: Into<View<L>>,
+        L: ApplyFalloff<Visibility> + Sync,
+        I: InputGrid + Sync,
+        I::Grid: Sync,
+        V: VisionDistance + Sync,
+        F: FnMut(Coord, DirectionBitmap, Visibility),
+        Visibility: Attenuate<I::Opacity> + Send + Sync,
+    {
+        let _: ControlFlow<Infallible> = self.observe_with(
+            Parallel,
+            view.into(),
+            Coverage::All,
+            input_grid,
+            grid,
+            vision_distance,
+            initial_visibility,
+            |coord, bitmap, visibility| {
+                f(coord, bitmap, visibility);
+                ControlFlow::Continue(())
+            },
+        );
+    }
+}
+
+/// A viewer observing the grid in a batch
+#[derive(Debug, Clone, Copy)]
+pub struct Viewer<V, Visibility, L = NoFalloff> {
+    pub view: View<L>,
+    pub vision_distance: V,
+    pub initial_visibility: Visibility,
+}
+
+/// A pool of contexts shared by the threads observing a batch of viewers
+#[derive(Debug)]
+pub struct ContextPool<Visibility> {
+    mode: Mode,
+    contexts: Mutex<Vec<Context<Visibility>>>,
+}
+
+impl<Visibility> Default for ContextPool<Visibility> {
+    fn default() -> Self {
+        Self::new(Mode::default())
+    }
+}
+
+/// A context taken from a pool, which is returned to the pool when dropped
+struct PooledContext<'a, Visibility> {
+    pool: &'a ContextPool<Visibility>,
+    context: Context<Visibility>,
+}
+
+impl<Visibility> Drop for PooledContext<'_, Visibility> {
+    fn drop(&mut self) {
+        let context = mem::take(&mut self.context);
+        self.pool.contexts().push(context);
+    }
+}
+
+impl<Visibility> ContextPool<Visibility> {
+    pub fn new(mode: Mode) -> Self {
+        Self {
+            mode,
+            contexts: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn contexts(&self) -> MutexGuard<'_, Vec<Context<Visibility>>> {
+        // the contexts hold no state between uses, so a panic while one was in use can't
+        // leave the pool in a bad state
+        self.contexts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn take(&self) -> PooledContext<'_, Visibility> {
+        let mut context = self.contexts().pop().unwrap_or_default();
+        context.set_mode(self.mode);
+        PooledContext {
+            pool: self,
+            context,
+        }
+    }
+
+    /// Observe the grid from each viewer, spread across the rayon thread pool. For each
+    /// viewer, `sink` is called with the index of the viewer to get the function which is
+    /// called with each cell visible to that viewer, in the same way as by
+    /// `Context::for_each_visible`.
+    pub fn for_each_visible<L, I, V, S, F>(
+        &self,
+        viewers: &[Viewer<V, Visibility, L>],
+        input_grid: &I,
+        grid: &I::Grid,
+        sink: S,
+    ) where
+        L: ApplyFalloff<Visibility> + Sync,
+        I: InputGrid + Sync,
+        I::Grid: Sync,
+        V: VisionDistance + Sync,
+        S: Fn(usize) -> F + Sync,
+        F: FnMut(Coord, DirectionBitmap, Visibility),
+        Visibility: Attenuate<I::Opacity> + Send + Sync,
+    {
+        viewers.par_iter().enumerate().for_each_init(
+            || self.take(),
+            |pooled, (index, viewer)| {
+                pooled.context.for_each_visible(
+                    viewer.view,
+                    input_grid,
+                    grid,
+                    viewer.vision_distance,
+                    viewer.initial_visibility,
+                    sink(index),
+                );
+            },
+        );
+    }
+
+    /// Observe the grid from each viewer, spread across the rayon thread pool, returning
+    /// the cells visible to each viewer
+    pub fn visible<L, I, V>(
+        &self,
+        viewers: &[Viewer<V, Visibility, L>],
+        input_grid: &I,
+        grid: &I::Grid,
+    ) -> Vec<Vec<(Coord, DirectionBitmap, Visibility)>>
+    where
+        L: ApplyFalloff<Visibility> + Sync,
+        I: InputGrid + Sync,
+        I::Grid: Sync,
+        V: VisionDistance + Sync,
+        Visibility: Attenuate<I::Opacity> + Send + Sync,
+    {
+        viewers
+            .par_iter()
+            .map_init(
+                || self.take(),
+                |pooled, viewer| {
+                    let mut visible = Vec::new();
+                    pooled.context.for_each_visible(
+                        viewer.view,
+                        input_grid,
+                        grid,
+                        viewer.vision_distance,
+                        viewer.initial_visibility,
+                        |coord, bitmap, visibility| {
+                            visible.push((coord, bitmap, visibility))
+                        },
+                    );
+                    visible
+                },
+            )
+            .collect()
+    }
+}
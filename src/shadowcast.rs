@@ -1,27 +1,317 @@
 use crate::octants::*;
 use coord_2d::{Coord, Size};
 pub use direction::DirectionBitmap;
-use num_traits::Zero;
+use direction::{CardinalDirection, Direction};
+use num_traits::{NumCast, Zero};
 #[cfg(feature = "serialize")]
 use serde::{Deserialize, Serialize};
 use std::cmp;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::f64::consts::{FRAC_PI_4, PI};
 use std::mem;
-use std::ops::Sub;
+use std::ops::{ControlFlow, Sub};
 
 pub trait InputGrid {
     type Grid;
     type Opacity;
     fn size(&self, grid: &Self::Grid) -> Size;
     fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity;
+
+    /// The opacity of a cell to lines of sight travelling mostly in the given direction
+    /// when they enter it, so a cell queried with `North` is being looked at from the
+    /// south. Lines of sight along a diagonal are travelling in both of the directions
+    /// either side of it, and the cell is queried with each. Defaults to `get_opacity`.
+    fn get_directional_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        _direction: CardinalDirection,
+    ) -> Self::Opacity {
+        self.get_opacity(grid, coord)
+    }
+
+    /// The height of the top of a cell, in the same units as the eye height passed to
+    /// `View::eye_height`. Cells without a height (the default) are infinitely tall. An
+    /// opaque cell with a height only obstructs the view below its top, and any cell with a
+    /// height is only visible if its top can be seen.
+    fn get_height(&self, _grid: &Self::Grid, _coord: Coord) -> Option<i32> {
+        None
+    }
+
+    /// Which edges of the grid are joined. Coordinates along a wrapping axis are taken
+    /// modulo the size of the grid along that axis, and cells are always reported at
+    /// their coordinates within the grid. A cell which can be seen more than once by
+    /// looking around the world is only reported once.
+    fn wrap(&self, _grid: &Self::Grid) -> Wrap {
+        Wrap::None
+    }
+
+    /// Whether the grid extends forever in every direction. Any coordinate, including
+    /// negative ones, may be passed to `get_opacity` and `get_height`, and `size` and
+    /// `wrap` are ignored. Only the vision distance limits the scan. If it has no
+    /// `max_depth`, scanning stops before the first depth at which no cell is in range.
+    fn is_unbounded(&self, _grid: &Self::Grid) -> bool {
+        false
+    }
+
+    /// The opacity of the thin wall along an edge of a cell, or `None` (the default) if
+    /// there is no wall there. Only the north and west edges of cells are queried, as the
+    /// south and east edges of a cell are the north and west edges of its neighbours. Thin
+    /// walls obstruct the view in the same way as cells with the same opacity.
+    fn get_edge_opacity(
+        &self,
+        _grid: &Self::Grid,
+        _coord: Coord,
+        _edge: CellEdge,
+    ) -> Option<Self::Opacity> {
+        None
+    }
+}
+
+/// An input grid whose opacity depends on who is looking at it. Wrap it in `ForViewer`
+/// along with the viewer to observe the grid as seen by that viewer.
+pub trait ViewerInputGrid<Viewer: ?Sized>: InputGrid {
+    fn get_viewer_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        viewer: &Viewer,
+    ) -> Self::Opacity;
+
+    /// Like `InputGrid::get_directional_opacity`, but for the given viewer. Defaults to
+    /// `get_viewer_opacity`.
+    fn get_directional_viewer_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        _direction: CardinalDirection,
+        viewer: &Viewer,
+    ) -> Self::Opacity {
+        self.get_viewer_opacity(grid, coord, viewer)
+    }
+
+    /// Like `InputGrid::get_edge_opacity`, but for the given viewer. Defaults to
+    /// `get_edge_opacity`.
+    fn get_edge_viewer_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        edge: CellEdge,
+        _viewer: &Viewer,
+    ) -> Option<Self::Opacity> {
+        self.get_edge_opacity(grid, coord, edge)
+    }
+}
+
+/// An input grid as seen by a particular viewer. It can be passed anywhere an
+/// `InputGrid` is expected, and is cheap to make for each viewer as it only borrows the
+/// grid and the viewer.
+#[derive(Debug)]
+pub struct ForViewer<'a, I: ?Sized, Viewer: ?Sized> {
+    input_grid: &'a I,
+    viewer: &'a Viewer,
+}
+
+impl<I: ?Sized, Viewer: ?Sized> Clone for ForViewer<'_, I, Viewer> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<I: ?Sized, Viewer: ?Sized> Copy for ForViewer<'_, I, Viewer> {}
+
+impl<'a, I: ?Sized, Viewer: ?Sized> ForViewer<'a, I, Viewer> {
+    pub const fn new(input_grid: &'a I, viewer: &'a Viewer) -> Self {
+        Self { input_grid, viewer }
+    }
+
+    pub const fn input_grid(&self) -> &'a I {
+        self.input_grid
+    }
+
+    pub const fn viewer(&self) -> &'a Viewer {
+        self.viewer
+    }
+}
+
+impl<I, Viewer> InputGrid for ForViewer<'_, I, Viewer>
+where
+    I: ViewerInputGrid<Viewer> + ?Sized,
+    Viewer: ?Sized,
+{
+    type Grid = I::Grid;
+    type Opacity = I::Opacity;
+    fn size(&self, grid: &Self::Grid) -> Size {
+        self.input_grid.size(grid)
+    }
+    fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity {
+        self.input_grid.get_viewer_opacity(grid, coord, self.viewer)
+    }
+    fn get_directional_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        direction: CardinalDirection,
+    ) -> Self::Opacity {
+        self.input_grid.get_directional_viewer_opacity(
+            grid,
+            coord,
+            direction,
+            self.viewer,
+        )
+    }
+    fn get_height(&self, grid: &Self::Grid, coord: Coord) -> Option<i32> {
+        self.input_grid.get_height(grid, coord)
+    }
+    fn wrap(&self, grid: &Self::Grid) -> Wrap {
+        self.input_grid.wrap(grid)
+    }
+    fn is_unbounded(&self, grid: &Self::Grid) -> bool {
+        self.input_grid.is_unbounded(grid)
+    }
+    fn get_edge_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        edge: CellEdge,
+    ) -> Option<Self::Opacity> {
+        self.input_grid
+            .get_edge_viewer_opacity(grid, coord, edge, self.viewer)
+    }
+}
+
+/// An edge of a cell along which there may be a thin wall
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CellEdge {
+    North,
+    West,
+}
+
+/// Which edges of a grid are joined
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Wrap {
+    /// Nothing can be seen beyond the edges of the grid
+    #[default]
+    None,
+    /// The left and right edges are joined, making a cylinder
+    Horizontal,
+    /// The top and bottom edges are joined, making a cylinder
+    Vertical,
+    /// Both pairs of opposite edges are joined, making a torus
+    Both,
+}
+
+/// The extent of a grid along one axis
+#[derive(Debug, Clone, Copy)]
+enum Edges {
+    /// Only indices from 0 up to the size exist
+    Bounded(i32),
+    /// Indices are taken modulo the size
+    Wrapped(i32),
+    /// Every index exists
+    Unbounded,
+}
+
+impl Edges {
+    fn new(size: u32, wrapped: bool) -> Self {
+        let size = size as i32;
+        if wrapped {
+            Self::Wrapped(size)
+        } else {
+            Self::Bounded(size)
+        }
+    }
+
+    fn for_grid<I: InputGrid>(input_grid: &I, grid: &I::Grid) -> (Self, Self) {
+        if input_grid.is_unbounded(grid) {
+            return (Self::Unbounded, Self::Unbounded);
+        }
+        let size = input_grid.size(grid);
+        let wrap = input_grid.wrap(grid);
+        (
+            Self::new(size.width(), matches!(wrap, Wrap::Horizontal | Wrap::Both)),
+            Self::new(size.height(), matches!(wrap, Wrap::Vertical | Wrap::Both)),
+        )
+    }
+
+    fn size(self) -> Option<i32> {
+        match self {
+            Self::Bounded(size) | Self::Wrapped(size) => Some(size),
+            Self::Unbounded => None,
+        }
+    }
+
+    fn is_wrapped(self) -> bool {
+        matches!(self, Self::Wrapped(_))
+    }
+
+    fn contains(self, index: i32) -> bool {
+        match self {
+            Self::Bounded(size) => index >= 0 && index < size,
+            Self::Wrapped(_) | Self::Unbounded => true,
+        }
+    }
+
+    fn canonical(self, index: i32) -> i32 {
+        match self {
+            Self::Bounded(_) | Self::Unbounded => index,
+            Self::Wrapped(size) => index.rem_euclid(size),
+        }
+    }
+
+    /// The number of cells beyond the given index before the edge in the direction
+    /// given by the sign of `step`
+    fn space(self, index: i32, step: i32) -> i32 {
+        match self {
+            Self::Bounded(size) if step > 0 => size - index - 1,
+            Self::Bounded(_) => index,
+            Self::Wrapped(_) | Self::Unbounded => i32::MAX,
+        }
+    }
+
+    /// Whether the same cell can be seen more than once by scanning to the given depth
+    fn wraps_within(self, max_depth: i32) -> bool {
+        match self {
+            Self::Bounded(_) | Self::Unbounded => false,
+            Self::Wrapped(size) => size as i64 <= max_depth as i64 * 2,
+        }
+    }
+
+    /// The offsets of cells within `max_depth` of 0 which are equivalent to `offset`
+    fn images(self, offset: i32, max_depth: i32) -> Vec<i32> {
+        match self {
+            Self::Bounded(_) | Self::Unbounded => vec![offset],
+            Self::Wrapped(size) => {
+                let first =
+                    (-max_depth).div_euclid(size) * size + offset.rem_euclid(size);
+                (0..)
+                    .map(|i| first + i * size)
+                    .skip_while(|&image| image < -max_depth)
+                    .take_while(|&image| image <= max_depth)
+                    .collect()
+            }
+        }
+    }
 }
 
 pub trait VisionDistance: Copy {
     fn in_range(self, delta: Coord) -> bool;
+
+    /// An upper bound on `max(abs(delta.x), abs(delta.y))` for every `delta` that is
+    /// `in_range`. Scanning stops at this depth from the eye rather than continuing to
+    /// the edge of the grid. The default of `None` means no bound is known.
+    fn max_depth(self) -> Option<u32> {
+        None
+    }
 }
 
 pub mod vision_distance {
     use super::VisionDistance;
     use coord_2d::Coord;
+    use direction::Direction;
     #[cfg(feature = "serialize")]
     use serde::{Deserialize, Serialize};
     use std::cmp;
@@ -36,6 +326,8 @@ pub mod vision_distance {
         pub const fn new_squared(distance_squared: u32) -> Self {
             Self { distance_squared }
         }
+        /// The square of the distance must fit in a `u32`, so the distance must be less
+        /// than 65536
         pub const fn new(distance: u32) -> Self {
             Self::new_squared(distance * distance)
         }
@@ -46,7 +338,19 @@ pub mod vision_distance {
 
     impl VisionDistance for Circle {
         fn in_range(self, delta: Coord) -> bool {
-            ((delta.x * delta.x + delta.y * delta.y) as u32) <= self.distance_squared
+            let (x, y) = (delta.x as i64, delta.y as i64);
+            x * x + y * y <= self.distance_squared as i64
+        }
+        fn max_depth(self) -> Option<u32> {
+            // integer square root, correcting for any rounding in the float estimate
+            let mut depth = (self.distance_squared as f64).sqrt() as u64;
+            while depth * depth > self.distance_squared as u64 {
+                depth -= 1;
+            }
+            while (depth + 1) * (depth + 1) <= self.distance_squared as u64 {
+                depth += 1;
+            }
+            Some(depth as u32)
         }
     }
 
@@ -67,7 +371,10 @@ pub mod vision_distance {
 
     impl VisionDistance for Square {
         fn in_range(self, delta: Coord) -> bool {
-            cmp::max(delta.x.abs(), delta.y.abs()) as u32 <= self.distance
+            cmp::max(delta.x.unsigned_abs(), delta.y.unsigned_abs()) <= self.distance
+        }
+        fn max_depth(self) -> Option<u32> {
+            Some(self.distance)
         }
     }
 
@@ -88,15 +395,688 @@ pub mod vision_distance {
 
     impl VisionDistance for Diamond {
         fn in_range(self, delta: Coord) -> bool {
-            ((delta.x.abs() + delta.y.abs()) as u32) <= self.distance
+            delta.x.unsigned_abs() as u64 + delta.y.unsigned_abs() as u64
+                <= self.distance as u64
+        }
+        fn max_depth(self) -> Option<u32> {
+            Some(self.distance)
+        }
+    }
+
+    /// An ellipse with separate horizontal and vertical radii, which needn't be whole
+    /// numbers of cells. A circle with a fractional radius is an ellipse whose radii are
+    /// equal.
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+    #[derive(Debug, Clone, Copy)]
+    pub struct Ellipse {
+        radius_x: f64,
+        radius_y: f64,
+    }
+
+    impl Ellipse {
+        pub const fn new(radius_x: f64, radius_y: f64) -> Self {
+            Self { radius_x, radius_y }
+        }
+        pub const fn circle(radius: f64) -> Self {
+            Self::new(radius, radius)
+        }
+        pub const fn radius_x(self) -> f64 {
+            self.radius_x
+        }
+        pub const fn radius_y(self) -> f64 {
+            self.radius_y
+        }
+    }
+
+    impl VisionDistance for Ellipse {
+        fn in_range(self, delta: Coord) -> bool {
+            let (x, y) = (delta.x as f64, delta.y as f64);
+            if x.abs() > self.radius_x || y.abs() > self.radius_y {
+                // also excludes everything off the axis of an ellipse with a zero radius
+                return false;
+            }
+            // (x / rx)^2 + (y / ry)^2 <= 1, multiplied through by (rx * ry)^2 so that
+            // whole radii are compared exactly
+            let (x, y) = (x * self.radius_y, y * self.radius_x);
+            let r = self.radius_x * self.radius_y;
+            x * x + y * y <= r * r
+        }
+        fn max_depth(self) -> Option<u32> {
+            Some(self.radius_x.max(self.radius_y) as u32)
+        }
+    }
+
+    /// A range which is longest in the direction the viewer is facing, tapering smoothly
+    /// to the sides and shortest behind. The facing angle is in radians, measured as for
+    /// `Cone`. Ranges are in cells.
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+    #[derive(Debug, Clone, Copy)]
+    pub struct Facing {
+        facing: f64,
+        forward: f64,
+        side: f64,
+        rear: f64,
+    }
+
+    impl Facing {
+        pub const fn new(facing: f64, forward: f64, side: f64, rear: f64) -> Self {
+            Self {
+                facing,
+                forward,
+                side,
+                rear,
+            }
+        }
+        pub fn from_direction(
+            direction: Direction,
+            forward: f64,
+            side: f64,
+            rear: f64,
+        ) -> Self {
+            let coord = direction.coord();
+            Self::new((coord.y as f64).atan2(coord.x as f64), forward, side, rear)
+        }
+        pub const fn facing(self) -> f64 {
+            self.facing
+        }
+        pub const fn forward(self) -> f64 {
+            self.forward
+        }
+        pub const fn side(self) -> f64 {
+            self.side
+        }
+        pub const fn rear(self) -> f64 {
+            self.rear
+        }
+
+        /// The range at an angle from the facing direction whose cosine is `cos`. The
+        /// range varies with the cosine of the angle from the side range, towards the
+        /// forward range in front and the rear range behind.
+        fn range(self, cos: f64) -> f64 {
+            if cos >= 0.0 {
+                self.side + (self.forward - self.side) * cos
+            } else {
+                self.side + (self.side - self.rear) * cos
+            }
+        }
+    }
+
+    impl VisionDistance for Facing {
+        fn in_range(self, delta: Coord) -> bool {
+            if delta == Coord::new(0, 0) {
+                return true;
+            }
+            let (x, y) = (delta.x as f64, delta.y as f64);
+            let distance = x.hypot(y);
+            let cos = (x * self.facing.cos() + y * self.facing.sin()) / distance;
+            distance <= self.range(cos)
+        }
+        fn max_depth(self) -> Option<u32> {
+            Some(self.forward.max(self.side).max(self.rear) as u32)
+        }
+    }
+
+    /// A vision template, such as a keyhole or a cross, drawn as a square of cells centred
+    /// on the eye. References to masks are vision distances.
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Mask {
+        radius: u32,
+        /// row-major, with sides of `2 * radius + 1` cells
+        cells: Vec<bool>,
+    }
+
+    impl Mask {
+        /// A mask extending `radius` cells from the eye in each direction, in which the
+        /// offsets from the eye for which `f` returns true are in range
+        pub fn from_fn<F: FnMut(Coord) -> bool>(radius: u32, mut f: F) -> Self {
+            let side = 2 * radius as i32 + 1;
+            let cells = (0..side)
+                .flat_map(|y| (0..side).map(move |x| Coord::new(x, y)))
+                .map(|coord| f(coord - Coord::new(radius as i32, radius as i32)))
+                .collect();
+            Self { radius, cells }
+        }
+
+        /// A mask drawn as a square of rows with an odd number of cells along each
+        /// side, in which the cells drawn with a '#' are in range. The eye is in the
+        /// centre of the square.
+        ///
+        /// # Panics
+        ///
+        /// Panics if there is an even number of rows, or if any row has a different
+        /// number of characters than there are rows.
+        pub fn from_strs(rows: &[&str]) -> Self {
+            let side = rows.len();
+            assert!(side % 2 == 1, "mask must have an odd number of rows");
+            let mut cells = Vec::with_capacity(side * side);
+            for row in rows {
+                let start = cells.len();
+                cells.extend(row.chars().map(|ch| ch == '#'));
+                assert_eq!(cells.len() - start, side, "mask must be square");
+            }
+            let radius = (side / 2) as u32;
+            Self { radius, cells }
+        }
+
+        pub fn radius(&self) -> u32 {
+            self.radius
+        }
+
+        fn index(&self, delta: Coord) -> Option<usize> {
+            if delta.x.unsigned_abs() > self.radius
+                || delta.y.unsigned_abs() > self.radius
+            {
+                return None;
+            }
+            let radius = self.radius as i32;
+            let side = 2 * radius as usize + 1;
+            Some((delta.y + radius) as usize * side + (delta.x + radius) as usize)
+        }
+    }
+
+    impl VisionDistance for &Mask {
+        fn in_range(self, delta: Coord) -> bool {
+            self.index(delta)
+                .and_then(|index| self.cells.get(index))
+                .cloned()
+                .unwrap_or(false)
+        }
+        fn max_depth(self) -> Option<u32> {
+            // the furthest cell in range, which may be nearer than the edge of the mask
+            let radius = self.radius as i32;
+            let side = 2 * radius + 1;
+            let depth = (0..side)
+                .flat_map(|y| (0..side).map(move |x| Coord::new(x - radius, y - radius)))
+                .filter(|&delta| self.in_range(delta))
+                .map(|delta| cmp::max(delta.x.abs(), delta.y.abs()) as u32)
+                .max();
+            Some(depth.unwrap_or(0))
+        }
+    }
+}
+
+/// Combining the visibilities of lines of sight. This is implemented for every ordered
+/// type by taking the greatest or least visibility.
+pub trait MergeVisibility: Copy + PartialEq {
+    /// The visibility of a cell seen along either of two lines of sight
+    fn most_visible(self, other: Self) -> Self;
+
+    /// The visibility beyond two things, both of which a line of sight passes through
+    fn least_visible(self, other: Self) -> Self;
+}
+
+impl<T: Copy + PartialOrd> MergeVisibility for T {
+    fn most_visible(self, other: Self) -> Self {
+        if other > self {
+            other
+        } else {
+            self
+        }
+    }
+
+    fn least_visible(self, other: Self) -> Self {
+        if other < self {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+/// How the visibility of lines of sight is reduced by passing through things with a given
+/// opacity. Every type which can be compared with and have subtracted from it the opacity
+/// attenuates subtractively: its opacity is subtracted from the visibility, and nothing
+/// can be seen beyond something at least as opaque as the visibility. The `attenuation`
+/// module has opacities which attenuate visibility in other ways.
+pub trait Attenuate<Opacity>: MergeVisibility {
+    /// The visibility beyond something with the given opacity, or `None` if nothing can be
+    /// seen beyond it
+    fn attenuate(self, opacity: Opacity) -> Option<Self>;
+}
+
+impl<Visibility, Opacity> Attenuate<Opacity> for Visibility
+where
+    Visibility: MergeVisibility + PartialOrd<Opacity> + Sub<Opacity, Output = Visibility>,
+{
+    fn attenuate(self, opacity: Opacity) -> Option<Self> {
+        if self > opacity {
+            Some(self - opacity)
+        } else {
+            None
+        }
+    }
+}
+
+pub mod attenuation {
+    use super::Attenuate;
+    #[cfg(feature = "serialize")]
+    use serde::{Deserialize, Serialize};
+
+    /// An opacity which lets through a fraction of the visibility, so that visibility
+    /// falls off exponentially with the distance travelled through a uniform medium such
+    /// as fog or smoke, following the Beer-Lambert law. Nothing can be seen beyond
+    /// something which lets through nothing.
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+    #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+    pub struct Multiplicative<T> {
+        transmittance: T,
+    }
+
+    impl<T> Multiplicative<T> {
+        /// The fraction of visibility which is let through, between 0 and 1. Something
+        /// with optical depth `d` lets through `exp(-d)` of the visibility.
+        pub const fn new(transmittance: T) -> Self {
+            Self { transmittance }
+        }
+    }
+
+    impl<T: Copy> Multiplicative<T> {
+        pub fn transmittance(self) -> T {
+            self.transmittance
+        }
+    }
+
+    /// An opacity which leaves the visibility unchanged if it's less than the visibility,
+    /// and otherwise blocks the view entirely
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Threshold<T> {
+        opacity: T,
+    }
+
+    impl<T> Threshold<T> {
+        pub const fn new(opacity: T) -> Self {
+            Self { opacity }
+        }
+    }
+
+    impl<T: Copy> Threshold<T> {
+        pub fn opacity(self) -> T {
+            self.opacity
+        }
+    }
+
+    macro_rules! multiplicative {
+        ($($t:ty),*) => {
+            $(
+                impl Attenuate<Multiplicative<$t>> for $t {
+                    fn attenuate(self, opacity: Multiplicative<$t>) -> Option<Self> {
+                        let visibility = self * opacity.transmittance;
+                        if visibility > 0.0 {
+                            Some(visibility)
+                        } else {
+                            None
+                        }
+                    }
+                }
+            )*
+        };
+    }
+
+    macro_rules! threshold {
+        ($($t:ty),*) => {
+            $(
+                impl Attenuate<Threshold<$t>> for $t {
+                    fn attenuate(self, opacity: Threshold<$t>) -> Option<Self> {
+                        if self > opacity.opacity {
+                            Some(self)
+                        } else {
+                            None
+                        }
+                    }
+                }
+            )*
+        };
+    }
+
+    multiplicative!(f32, f64);
+    threshold!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+}
+
+/// How the visibility of cells falls off with their distance from the eye, such as the
+/// light of a torch dimming away from its flame
+pub trait Falloff: Copy {
+    /// The fraction of visibility which remains at `distance` cells from the eye, between
+    /// 0 and 1. This must not increase with distance, as scanning stops at the depth where
+    /// the remaining visibility reaches zero.
+    fn factor(self, distance: f64) -> f64;
+}
+
+/// The absence of a falloff, so cells are reported with the visibility with which they are
+/// seen. This is the falloff of a new `View`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoFalloff;
+
+/// A falloff which can be applied to visibilities of a particular type. It is implemented
+/// by `NoFalloff` for every type of visibility, and by every `Falloff` for numeric
+/// visibilities, whose values are multiplied by its factor.
+pub trait ApplyFalloff<Visibility>: Copy {
+    /// Whether any visibility falls off at all
+    const FALLS_OFF: bool = true;
+
+    /// The visibility which remains of `visibility` at `distance` cells from the eye, or
+    /// `None` if it has fallen off to nothing
+    fn apply(self, visibility: Visibility, distance: f64) -> Option<Visibility>;
+}
+
+impl<Visibility> ApplyFalloff<Visibility> for NoFalloff {
+    const FALLS_OFF: bool = false;
+
+    fn apply(self, visibility: Visibility, _distance: f64) -> Option<Visibility> {
+        Some(visibility)
+    }
+}
+
+impl<Visibility, L> ApplyFalloff<Visibility> for L
+where
+    Visibility: Zero + NumCast,
+    L: Falloff,
+{
+    fn apply(self, visibility: Visibility, distance: f64) -> Option<Visibility> {
+        let factor = self.factor(distance).clamp(0.0, 1.0);
+        visibility
+            .to_f64()
+            .and_then(|visibility| NumCast::from(visibility * factor))
+            .filter(|visibility: &Visibility| !visibility.is_zero())
+    }
+}
+
+pub mod falloff {
+    use super::Falloff;
+    #[cfg(feature = "serialize")]
+    use serde::{Deserialize, Serialize};
+
+    /// Visibility falls off linearly, reaching zero at `range` cells from the eye
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+    #[derive(Debug, Clone, Copy)]
+    pub struct Linear {
+        range: f64,
+    }
+
+    impl Linear {
+        pub const fn new(range: f64) -> Self {
+            Self { range }
+        }
+        pub const fn range(self) -> f64 {
+            self.range
+        }
+    }
+
+    impl Falloff for Linear {
+        fn factor(self, distance: f64) -> f64 {
+            1.0 - distance / self.range
+        }
+    }
+
+    /// Visibility falls off with the square of the distance from the eye, such that it is
+    /// halved at `half_distance` cells from the eye. It never reaches zero, but scanning
+    /// still stops once the remaining visibility rounds down to zero.
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+    #[derive(Debug, Clone, Copy)]
+    pub struct InverseSquare {
+        half_distance: f64,
+    }
+
+    impl InverseSquare {
+        pub const fn new(half_distance: f64) -> Self {
+            Self { half_distance }
+        }
+        pub const fn half_distance(self) -> f64 {
+            self.half_distance
+        }
+    }
+
+    impl Falloff for InverseSquare {
+        fn factor(self, distance: f64) -> f64 {
+            let ratio = distance / self.half_distance;
+            1.0 / (1.0 + ratio * ratio)
+        }
+    }
+
+    /// Visibility falls off according to a function from the distance from the eye in
+    /// cells to the fraction of visibility which remains
+    #[derive(Debug, Clone, Copy)]
+    pub struct Custom<F> {
+        curve: F,
+    }
+
+    impl<F: Fn(f64) -> f64 + Copy> Custom<F> {
+        pub fn new(curve: F) -> Self {
+            Self { curve }
+        }
+    }
+
+    impl<F: Fn(f64) -> f64 + Copy> Falloff for Custom<F> {
+        fn factor(self, distance: f64) -> f64 {
+            (self.curve)(distance)
+        }
+    }
+}
+
+/// A view cone, for restricting the area scanned to the directions a viewer is facing.
+/// Angles are in radians, and are measured clockwise from the positive x axis (east),
+/// as the y axis points south.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy)]
+pub struct Cone {
+    facing: f64,
+    half_angle: f64,
+}
+
+impl Cone {
+    pub const fn new(facing: f64, half_angle: f64) -> Self {
+        Self { facing, half_angle }
+    }
+    pub fn from_direction(direction: Direction, half_angle: f64) -> Self {
+        let coord = direction.coord();
+        Self::new((coord.y as f64).atan2(coord.x as f64), half_angle)
+    }
+    pub const fn facing(self) -> f64 {
+        self.facing
+    }
+    pub const fn half_angle(self) -> f64 {
+        self.half_angle
+    }
+
+    /// The ranges of gradients of the given octant which lie within the cone. There are
+    /// none if the cone doesn't intersect the octant, and two if the gap behind the cone
+    /// lies within the octant.
+    fn octant_wedges<O: Octant>(self, octant: &O) -> [Option<Wedge>; 2] {
+        if self.half_angle >= PI {
+            return [Some(Wedge::OCTANT), None];
+        }
+        let angle_of = |direction: direction::CardinalDirection| {
+            let coord = direction.coord();
+            (coord.y as f64).atan2(coord.x as f64)
+        };
+        let wrap = |angle: f64| {
+            let angle = angle.rem_euclid(2.0 * PI);
+            if angle > PI {
+                angle - 2.0 * PI
+            } else {
+                angle
+            }
+        };
+        let depth_angle = angle_of(octant.depth_direction());
+        let sign = wrap(angle_of(octant.lateral_direction()) - depth_angle).signum();
+        // the facing angle relative to the octant, where the octant covers 0 to PI/4
+        let facing = sign * wrap(self.facing - depth_angle);
+        // As the cone is narrower than a full turn, at most two of these ranges intersect
+        // the octant, and they are in order of angle.
+        let mut ranges = [None; 2];
+        for facing in [facing - 2.0 * PI, facing, facing + 2.0 * PI] {
+            let low = (facing - self.half_angle).max(0.0);
+            let high = (facing + self.half_angle).min(FRAC_PI_4);
+            if low <= high {
+                match ranges {
+                    [None, _] => ranges[0] = Some((low, high)),
+                    [Some((first_low, first_high)), _] if low <= first_high => {
+                        ranges[0] = Some((first_low, first_high.max(high)))
+                    }
+                    _ => ranges[1] = Some((low, high)),
+                }
+            }
+        }
+        ranges.map(|range| {
+            range.map(|(low, high)| {
+                const DEPTH: i64 = Wedge::CONE_GRADIENT_DEPTH;
+                let mut low = cmp::max((low.tan() * DEPTH as f64).floor() as i64, 0);
+                let mut high = cmp::min((high.tan() * DEPTH as f64).ceil() as i64, DEPTH);
+                // Cells along the edge of the cone are still scanned even if the edge of the
+                // cone lies along the edge of the octant.
+                if low >= high {
+                    if high < DEPTH {
+                        high = low + 1;
+                    } else {
+                        low = high - 1;
+                    }
+                }
+                Wedge {
+                    min_gradient: Gradient::new(low, DEPTH),
+                    max_gradient: Gradient::new(high, DEPTH),
+                }
+            })
+        })
+    }
+
+    /// The range of gradients of the given octant from the start of the first of its
+    /// wedges within the cone to the end of the last, or `None` if the cone doesn't
+    /// intersect the octant
+    fn octant_hull<O: Octant>(self, octant: &O) -> Option<Wedge> {
+        let [first, last] = self.octant_wedges(octant);
+        let first = first?;
+        Some(Wedge {
+            min_gradient: first.min_gradient,
+            max_gradient: last.unwrap_or(first).max_gradient,
+        })
+    }
+}
+
+/// Where the grid is observed from and how. Everything which observes the grid takes a
+/// view, or just the coord of the eye's cell, which is the view returned by `View::new`. A
+/// view starts out as an eye in the centre of a single cell, seeing in every direction,
+/// and each of the other options can be combined with any of the others.
+#[derive(Debug, Clone, Copy)]
+pub struct View<L = NoFalloff> {
+    eye: Eye,
+    cone: Option<Cone>,
+    falloff: L,
+    walls: bool,
+}
+
+impl View {
+    pub fn new(coord: Coord) -> Self {
+        Self {
+            eye: Eye::cell(coord),
+            cone: None,
+            falloff: NoFalloff,
+            walls: false,
+        }
+    }
+}
+
+impl From<Coord> for View {
+    fn from(coord: Coord) -> Self {
+        Self::new(coord)
+    }
+}
+
+impl<L> View<L> {
+    /// The eye's cell, or the top-left cell of a viewer with a footprint
+    pub fn coord(&self) -> Coord {
+        self.eye.coord
+    }
+
+    /// Offset the eye from the centre of its cell by `eye_offset`, measured in sixteenths
+    /// of a cell. Each component of the offset is clamped to the range -7 to 7 so the eye
+    /// is always strictly within its cell.
+    pub fn eye_offset(mut self, eye_offset: Coord) -> Self {
+        self.eye.offset = eye_offset;
+        self
+    }
+
+    /// Take the heights of cells (see `InputGrid::get_height`) into account. A cell is
+    /// only visible if its top rises above the line of sight from an eye at the given
+    /// height over any opaque cells between it and the eye.
+    pub fn eye_height(mut self, eye_height: i32) -> Self {
+        self.eye.height = Some(eye_height);
+        self
+    }
+
+    /// Observe from a viewer occupying the rectangle of cells of the given size whose
+    /// top-left cell is the eye's cell. A cell is visible if it is visible from any of the
+    /// viewer's cells. Each cell is reported once, with all the edges visible from any of
+    /// the viewer's cells, and the greatest visibility with which it is seen.
+    ///
+    /// This scans from each of the viewer's cells in turn, except those surrounded by
+    /// opaque cells of the viewer, and sorts everything seen to merge it, so it costs a
+    /// little more than observing from each cell separately.
+    pub fn footprint(mut self, footprint: Size) -> Self {
+        self.eye.footprint = footprint;
+        self
+    }
+
+    /// Only report the cells which intersect the given cone, and the thin walls within it.
+    /// The cells just outside the cone are still scanned, to find out what can be seen
+    /// within it, so nothing is reported which couldn't be seen without the cone.
+    pub fn cone(mut self, cone: Cone) -> Self {
+        self.cone = Some(cone);
+        self
+    }
+
+    /// Multiply the visibility of each cell by the factor given by `falloff` for its
+    /// distance from the eye. Cells whose visibility falls off to zero aren't reported, and
+    /// scanning stops at the depth where nothing more can be seen.
+    pub fn falloff<M: Falloff>(self, falloff: M) -> View<M> {
+        View {
+            eye: self.eye,
+            cone: self.cone,
+            falloff,
+            walls: self.walls,
         }
     }
+
+    /// Collect the thin walls which can be seen, to be taken from the context with
+    /// `Context::visible_walls` once the grid has been observed
+    pub fn walls(mut self) -> Self {
+        self.walls = true;
+        self
+    }
+
+    /// Whether the eye is in the centre of a single cell, and the heights of cells are
+    /// ignored
+    pub(crate) fn is_centred_in_cell(&self) -> bool {
+        self.eye.footprint == Size::new(1, 1)
+            && self.eye.offset == Coord::new(0, 0)
+            && self.eye.height.is_none()
+    }
+}
+
+/// The number of sub-cells along each axis of a cell. Gradients are measured in sub-cells
+/// so that the eye can be positioned within its cell.
+const SUB_CELLS: i64 = 16;
+const HALF_CELL: i64 = SUB_CELLS / 2;
+
+/// The distance in sub-cells from the eye at which the lateral range of a row is measured.
+/// This is usually the centre of the row, but the centre of the eye's own row may be behind
+/// the eye, so the far side of the row is used instead.
+fn effective_gradient_depth(depth: i32, offset_depth: i64) -> i64 {
+    let mid_gradient_depth = depth as i64 * SUB_CELLS - offset_depth;
+    if depth == 0 {
+        mid_gradient_depth + HALF_CELL
+    } else {
+        mid_gradient_depth
+    }
 }
 
+/// Gradients are products of depths in sub-cells, which are themselves multiplied
+/// together when comparing gradients, so 64-bit arithmetic is used to avoid overflow at
+/// large depths.
 #[derive(Debug, Clone, Copy)]
 struct Gradient {
-    lateral: i32,
-    depth: i32,
+    lateral: i64,
+    depth: i64,
 }
 
 impl PartialEq for Gradient {
@@ -106,24 +1086,636 @@ impl PartialEq for Gradient {
 }
 
 impl Gradient {
-    fn new(lateral: i32, depth: i32) -> Self {
+    fn new(lateral: i64, depth: i64) -> Self {
         Self { lateral, depth }
     }
+
+    /// Assumes both depths are positive
+    fn is_below(self, other: Self) -> bool {
+        self.lateral * other.depth < other.lateral * self.depth
+    }
+
+    fn clamp(self, min: Self, max: Self) -> Self {
+        if self.is_below(min) {
+            min
+        } else if max.is_below(self) {
+            max
+        } else {
+            self
+        }
+    }
+}
+
+/// The slope of a line from the eye in the vertical plane, as the change in height over
+/// the depth in sub-cells
+#[derive(Debug, Clone, Copy)]
+struct VerticalGradient {
+    rise: i64,
+    run: i64,
+}
+
+impl PartialEq for VerticalGradient {
+    fn eq(&self, other: &Self) -> bool {
+        self.rise * other.run == other.rise * self.run
+    }
+}
+
+impl VerticalGradient {
+    fn is_above(self, other: Self) -> bool {
+        self.rise * other.run > other.rise * self.run
+    }
+}
+
+/// Whether something whose top is at the given gradient can be seen over the horizon.
+/// A top of `None` is infinitely high, and a horizon of `None` obstructs nothing.
+fn above_horizon(
+    top: Option<VerticalGradient>,
+    horizon: Option<VerticalGradient>,
+) -> bool {
+    match (top, horizon) {
+        (Some(top), Some(horizon)) => top.is_above(horizon),
+        _ => true,
+    }
+}
+
+/// The higher of two horizons, where a horizon of `None` obstructs nothing
+fn higher_horizon(
+    a: Option<VerticalGradient>,
+    b: Option<VerticalGradient>,
+) -> Option<VerticalGradient> {
+    match (a, b) {
+        (Some(a), Some(b)) if b.is_above(a) => Some(b),
+        (Some(a), _) => Some(a),
+        (None, b) => b,
+    }
+}
+
+/// How much can be seen beyond part of a row, through the lines of sight which pass
+/// through it
+#[derive(Debug, Clone, Copy)]
+struct Passage<Visibility> {
+    visibility: Visibility,
+    horizon: Option<VerticalGradient>,
+    blocked: bool,
+}
+
+impl<Visibility: MergeVisibility> Passage<Visibility> {
+    fn differs(self, other: Self) -> bool {
+        if self.blocked || other.blocked {
+            self.blocked != other.blocked
+        } else {
+            self.visibility != other.visibility || self.horizon != other.horizon
+        }
+    }
+
+    /// Lines of sight which pass through two things see as little as they would through
+    /// either of them
+    fn most_obstructed(self, other: Self) -> Self {
+        if self.blocked || other.blocked {
+            return if self.blocked { self } else { other };
+        }
+        Self {
+            visibility: self.visibility.least_visible(other.visibility),
+            horizon: higher_horizon(self.horizon, other.horizon),
+            blocked: false,
+        }
+    }
+
+    /// Continue through a thin wall with the given opacity, if there is one
+    fn through<O>(self, opacity: Option<O>) -> Self
+    where
+        Visibility: Attenuate<O>,
+    {
+        match opacity {
+            Some(opacity) if !self.blocked => match self.visibility.attenuate(opacity) {
+                Some(visibility) => Self { visibility, ..self },
+                None => Self {
+                    blocked: true,
+                    ..self
+                },
+            },
+            _ => self,
+        }
+    }
+}
+
+/// Add the section of the next row seen through the part of the current row between
+/// `min_gradient` and `gradient` to `next`, unless it's blocked, and start the next part at
+/// `gradient`
+fn split_section<Visibility>(
+    next: &mut Vec<ScanParams<Visibility>>,
+    min_gradient: &mut Gradient,
+    min_inclusive: &mut bool,
+    depth: i32,
+    passage: Passage<Visibility>,
+    gradient: Gradient,
+    symmetric: bool,
+) {
+    if !passage.blocked {
+        next.push(ScanParams {
+            min_gradient: *min_gradient,
+            max_gradient: gradient,
+            min_inclusive: *min_inclusive,
+            depth: depth + 1,
+            visibility: passage.visibility,
+            horizon: passage.horizon,
+        });
+    }
+    *min_gradient = gradient;
+    // Beveled transitions don't pass through the corners of cells, so there is no need to
+    // exclude the first cell of the next row.
+    *min_inclusive = symmetric;
+}
+
+/// A range of gradients within an octant
+#[derive(Debug, Clone, Copy)]
+struct Wedge {
+    min_gradient: Gradient,
+    max_gradient: Gradient,
+}
+
+impl Wedge {
+    const OCTANT: Self = Self {
+        min_gradient: Gradient {
+            lateral: 0,
+            depth: 1,
+        },
+        max_gradient: Gradient {
+            lateral: 1,
+            depth: 1,
+        },
+    };
+
+    /// The depth of gradients derived from view cones
+    const CONE_GRADIENT_DEPTH: i64 = 4096;
+
+    /// The range of laterals of the cells which the wedge passes through at the given
+    /// gradient depth, worked out in the same way as the range of a section in `scan`
+    fn lateral_range(self, gradient_depth: i64, offset_lateral: i64) -> (i64, i64) {
+        let Self {
+            min_gradient: min,
+            max_gradient: max,
+        } = self;
+        let eye_from_edge = HALF_CELL + offset_lateral;
+        (
+            (min.depth * eye_from_edge + min.lateral * gradient_depth)
+                .div_euclid(min.depth * SUB_CELLS),
+            (max.depth * eye_from_edge + max.lateral * gradient_depth - 1)
+                .div_euclid(max.depth * SUB_CELLS),
+        )
+    }
+
+    /// The range of laterals within two cells of the wedge at the given gradient depth
+    /// (see `Coverage::lateral_window`)
+    fn lateral_window(self, gradient_depth: i64, offset_lateral: i64) -> (i64, i64) {
+        let (min, max) = self.lateral_range(gradient_depth, offset_lateral);
+        (min - 2, max + 2)
+    }
+}
+
+/// How many cells either side of a changed cell are included in the shadow of
+/// `Coverage::Region`
+pub(crate) const REGION_MARGIN: i64 = 3;
+
+/// Which part of each octant is scanned
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Coverage {
+    All,
+    /// Just the wedge containing the cell at the given offset from the eye
+    Cell(Coord),
+    /// Just the cells whose visibility may depend on the cell at the given offset from the
+    /// eye
+    Region(Coord),
+}
+
+impl Coverage {
+    /// The wedge of the octant which is scanned. This is always the entire octant, so that
+    /// the cells of interest are seen exactly as they are when scanning everything, but see
+    /// `lateral_window`.
+    fn octant_wedge<O: Octant>(self, octant: &O) -> Option<Wedge> {
+        match self {
+            Self::All => Some(Wedge::OCTANT),
+            Self::Cell(delta) => {
+                let (lateral, depth) = octant.lateral_and_depth(delta);
+                (depth >= 1
+                    && lateral >= 0
+                    && lateral <= depth
+                    && octant.should_see(lateral))
+                .then_some(Wedge::OCTANT)
+            }
+            Self::Region(delta) => {
+                // cells behind the eye are never scanned by this octant
+                let (_, depth) = octant.lateral_and_depth(delta);
+                (depth >= 0).then_some(Wedge::OCTANT)
+            }
+        }
+    }
+
+    /// The gradients of the edges of the shadow of the cells of interest, or `None` if
+    /// every cell is of interest
+    fn shadow<O: Octant>(
+        self,
+        octant: &O,
+        (offset_lateral, offset_depth): (i64, i64),
+    ) -> Option<(Gradient, Gradient)> {
+        let (delta, margin) = match self {
+            Self::Cell(delta) => (delta, 0),
+            // Changing a cell affects the cells whose lateral windows contain it. Those
+            // cells' shadows pass within two cells of it, and so lie within three cells
+            // of it, so their lateral windows lie within the window of this wider shadow.
+            Self::Region(delta) => (delta, REGION_MARGIN),
+            Self::All => return None,
+        };
+        let (lateral, depth) = octant.lateral_and_depth(delta);
+        let gradient_depth = effective_gradient_depth(depth, offset_depth);
+        Some((
+            Gradient::new(
+                (lateral as i64 - margin) * SUB_CELLS - HALF_CELL - offset_lateral,
+                gradient_depth,
+            ),
+            Gradient::new(
+                (lateral as i64 + margin) * SUB_CELLS + HALF_CELL - offset_lateral,
+                gradient_depth,
+            ),
+        ))
+    }
+
+    /// The range of laterals scanned at a given gradient depth when only some cells are
+    /// of interest. The wedges which are scanned are the same as when scanning the entire
+    /// octant, but each wedge is only scanned for cells within two cells of the shadow of
+    /// the cells of interest. Transitions in opacity outside this range only affect the
+    /// gradients of wedges which start or end outside it, so the wedges which cover the
+    /// cells of interest are exactly as they would be when scanning the entire octant.
+    fn lateral_window<O: Octant>(
+        self,
+        octant: &O,
+        gradient_depth: i64,
+        (offset_lateral, offset_depth): (i64, i64),
+    ) -> Option<(i64, i64)> {
+        let (min_gradient, max_gradient) =
+            self.shadow(octant, (offset_lateral, offset_depth))?;
+        let shadow = Wedge {
+            min_gradient,
+            max_gradient,
+        };
+        Some(shadow.lateral_window(gradient_depth, offset_lateral))
+    }
+}
+
+/// Whether changing the cell at `changed` can affect how the cell at `delta` is seen, both
+/// relative to an eye in the centre of its cell
+pub(crate) fn region_effect(changed: Coord, delta: Coord) -> bool {
+    fn octant_effect<O: Octant>(octant: O, changed: Coord, delta: Coord) -> bool {
+        const OFFSETS: (i64, i64) = (0, 0);
+        let cell = Coverage::Cell(delta);
+        if cell.octant_wedge(&octant).is_none() {
+            return false;
+        }
+        let (lateral, depth) = octant.lateral_and_depth(changed);
+        let (_, cell_depth) = octant.lateral_and_depth(delta);
+        depth >= 0
+            && depth <= cell_depth
+            && cell
+                .lateral_window(&octant, effective_gradient_depth(depth, 0), OFFSETS)
+                .is_some_and(|(min, max)| min <= lateral as i64 && lateral as i64 <= max)
+    }
+    octant_effect(TopLeft, changed, delta)
+        || octant_effect(LeftTop, changed, delta)
+        || octant_effect(TopRight, changed, delta)
+        || octant_effect(RightTop, changed, delta)
+        || octant_effect(BottomLeft, changed, delta)
+        || octant_effect(LeftBottom, changed, delta)
+        || octant_effect(BottomRight, changed, delta)
+        || octant_effect(RightBottom, changed, delta)
+}
+
+/// The corners of a box containing every cell within `max_depth` of the eye which is
+/// scanned by `Coverage::Region(changed)`, and so every cell whose view can be affected by
+/// changing the cell at `changed`, all relative to an eye in the centre of its cell
+pub(crate) fn region_bounds(changed: Coord, max_depth: i32) -> Option<(Coord, Coord)> {
+    fn octant_bounds<O: Octant>(
+        octant: O,
+        changed: Coord,
+        max_depth: i32,
+    ) -> Option<(Coord, Coord)> {
+        const OFFSETS: (i64, i64) = (0, 0);
+        let region = Coverage::Region(changed);
+        region.octant_wedge(&octant)?;
+        let (_, min_depth) = octant.lateral_and_depth(changed);
+        if min_depth > max_depth {
+            return None;
+        }
+        // The lateral window grows linearly with depth, so its extremes are at the
+        // nearest and furthest depths.
+        let (near_min, near_max) = region.lateral_window(
+            &octant,
+            effective_gradient_depth(min_depth, 0),
+            OFFSETS,
+        )?;
+        let (far_min, far_max) = region.lateral_window(
+            &octant,
+            effective_gradient_depth(max_depth, 0),
+            OFFSETS,
+        )?;
+        let min_lateral = cmp::max(cmp::min(near_min, far_min), 0) as i32;
+        let max_lateral = cmp::min(cmp::max(near_max, far_max), max_depth as i64) as i32;
+        if min_lateral > max_lateral {
+            return None;
+        }
+        let a = octant.make_coord(Coord::new(0, 0), min_lateral, min_depth);
+        let b = octant.make_coord(Coord::new(0, 0), max_lateral, max_depth);
+        Some((
+            Coord::new(cmp::min(a.x, b.x), cmp::min(a.y, b.y)),
+            Coord::new(cmp::max(a.x, b.x), cmp::max(a.y, b.y)),
+        ))
+    }
+    [
+        octant_bounds(TopLeft, changed, max_depth),
+        octant_bounds(LeftTop, changed, max_depth),
+        octant_bounds(TopRight, changed, max_depth),
+        octant_bounds(RightTop, changed, max_depth),
+        octant_bounds(BottomLeft, changed, max_depth),
+        octant_bounds(LeftBottom, changed, max_depth),
+        octant_bounds(BottomRight, changed, max_depth),
+        octant_bounds(RightBottom, changed, max_depth),
+    ]
+    .into_iter()
+    .flatten()
+    .reduce(|(a_min, a_max), (b_min, b_max)| {
+        (
+            Coord::new(cmp::min(a_min.x, b_min.x), cmp::min(a_min.y, b_min.y)),
+            Coord::new(cmp::max(a_max.x, b_max.x), cmp::max(a_max.y, b_max.y)),
+        )
+    })
+}
+
+/// The rules used to decide which cells are visible
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Any cell which can be seen by a line from the centre of the eye is visible
+    #[default]
+    Asymmetric,
+    /// Transparent cells are only visible if their centre can be seen from the centre of
+    /// the eye, and opaque cells are treated as diamonds (beveled walls). A transparent
+    /// cell A is visible from another transparent cell B if and only if B is visible from
+    /// A.
+    Symmetric,
+    /// A cell is visible if it can be seen from any of several points within the eye's
+    /// cell. These are the centre of the cell, and the corners of a square centred on it
+    /// whose sides are the given number of eighths of the width of a cell (from 0 to 7).
+    /// Higher levels are more permissive, with level 0 being equivalent to `Asymmetric`.
+    /// Levels above 7 are treated as 7, as points on the edge of the eye's cell would see
+    /// along the edges of cells.
+    Permissive(u8),
+}
+
+impl Mode {
+    /// The points, in sub-cells relative to the centre of the eye's cell, from which
+    /// cells are observed by an eye at the given offset
+    fn eye_offsets(self, eye_offset: Coord) -> impl Iterator<Item = Coord> {
+        let radius = match self {
+            Self::Permissive(level) => cmp::min(level as i32, HALF_CELL as i32 - 1),
+            Self::Asymmetric | Self::Symmetric => 0,
+        };
+        let corners = [(-1, -1), (1, -1), (-1, 1), (1, 1)]
+            .into_iter()
+            .filter(move |_| radius > 0)
+            .map(move |(x, y)| Coord::new(x * radius, y * radius));
+        std::iter::once(Coord::new(0, 0))
+            .chain(corners)
+            .map(move |offset| clamp_eye_offset(offset + eye_offset))
+    }
+}
+
+/// The cells from which other cells are observed
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Eye {
+    /// the top-left cell of the viewer
+    coord: Coord,
+    /// the size of the viewer in cells
+    footprint: Size,
+    /// the position of the eye within each of the viewer's cells, relative to the centre
+    /// of the cell, in sub-cells
+    offset: Coord,
+    /// the height of the eye, if cell heights are taken into account
+    height: Option<i32>,
+}
+
+impl Eye {
+    pub(crate) fn cell(coord: Coord) -> Self {
+        Self {
+            coord,
+            footprint: Size::new(1, 1),
+            offset: Coord::new(0, 0),
+            height: None,
+        }
+    }
+}
+
+/// Keep the eye strictly within its cell, as an eye on the edge of a cell would see along
+/// the edges of cells
+fn clamp_eye_offset(eye_offset: Coord) -> Coord {
+    let limit = HALF_CELL as i32 - 1;
+    let clamp = |component: i32| component.clamp(-limit, limit);
+    Coord::new(clamp(eye_offset.x), clamp(eye_offset.y))
 }
 
-struct StaticParams<'a, I: 'a + InputGrid, Visibility, VisDist> {
+pub(crate) struct StaticParams<'a, I: 'a + InputGrid, Visibility, VisDist> {
     centre: Coord,
+    eye_offset: Coord,
+    eye_height: Option<i32>,
+    mode: Mode,
+    coverage: Coverage,
     vision_distance: VisDist,
+    max_depth: i32,
     input_grid: &'a I,
     grid: &'a I::Grid,
-    width: i32,
-    height: i32,
+    x_edges: Edges,
+    y_edges: Edges,
     initial_visibility: Visibility,
+    cone: Option<Cone>,
+    /// reduces the visibility of a cell given its distance in cells from the eye
+    falloff: Option<FalloffFn<'a, Visibility>>,
+    report_walls: bool,
 }
 
+type FalloffFn<'a, Visibility> =
+    Box<dyn Fn(Visibility, f64) -> Option<Visibility> + Sync + 'a>;
+
 impl<'a, I: InputGrid, Visibility, VisDist> StaticParams<'a, I, Visibility, VisDist> {
-    fn get_opacity(&self, coord: Coord) -> I::Opacity {
-        self.input_grid.get_opacity(&self.grid, coord)
+    fn get_opacity(&self, coord: Coord, direction: CardinalDirection) -> I::Opacity {
+        self.input_grid
+            .get_directional_opacity(self.grid, coord, direction)
+    }
+
+    fn in_bounds(&self, coord: Coord) -> bool {
+        self.x_edges.contains(coord.x) && self.y_edges.contains(coord.y)
+    }
+
+    /// The wedge of the octant which is scanned, or `None` if nothing in it is reported
+    fn octant_wedge<O: Octant>(&self, octant: &O) -> Option<Wedge> {
+        if let Some(cone) = self.cone {
+            cone.octant_hull(octant)?;
+        }
+        self.coverage.octant_wedge(octant)
+    }
+
+    /// The range of laterals scanned at a given gradient depth, or `None` if every cell of
+    /// the wedge is scanned. This is the lateral window of the coverage, and of the cone
+    /// if there is one, as the cells around a cone are only scanned to find out what can
+    /// be seen within it.
+    fn lateral_window<O: Octant>(
+        &self,
+        octant: &O,
+        gradient_depth: i64,
+        (offset_lateral, offset_depth): (i64, i64),
+    ) -> Option<(i64, i64)> {
+        let coverage_window = self.coverage.lateral_window(
+            octant,
+            gradient_depth,
+            (offset_lateral, offset_depth),
+        );
+        let cone_window = self
+            .cone
+            .and_then(|cone| cone.octant_hull(octant))
+            .map(|hull| hull.lateral_window(gradient_depth, offset_lateral));
+        match (coverage_window, cone_window) {
+            (Some((a_min, a_max)), Some((b_min, b_max))) => {
+                Some((cmp::max(a_min, b_min), cmp::min(a_max, b_max)))
+            }
+            (window, None) | (None, window) => window,
+        }
+    }
+
+    /// The wedges of the octant within which cells and walls are reported, or `None` if
+    /// they are reported anywhere in the octant
+    fn reported_wedges<O: Octant>(&self, octant: &O) -> Option<[Option<Wedge>; 2]> {
+        self.cone.map(|cone| cone.octant_wedges(octant))
+    }
+
+    /// Whether the results of scanning from the eye must be merged before being reported.
+    /// Cells may be seen from several eye cells, several points in each eye cell, or from
+    /// several directions on a wrapping grid.
+    fn merges(&self, eye: Eye) -> bool
+    where
+        Visibility: Attenuate<I::Opacity>,
+    {
+        eye.footprint != Size::new(1, 1)
+            || matches!(self.mode, Mode::Permissive(1..))
+            || self.x_edges.wraps_within(self.max_depth)
+            || self.y_edges.wraps_within(self.max_depth)
+    }
+
+    /// Whether the cell of the viewer at the given offset from its top-left cell is
+    /// surrounded by cells of the viewer which nothing can be seen through. Nothing can be
+    /// seen from such a cell that isn't reported as part of the viewer anyway, so there's
+    /// no need to scan from it.
+    fn enclosed(&self, eye: Eye, offset: Coord) -> bool
+    where
+        Visibility: Attenuate<I::Opacity>,
+    {
+        let interior = offset.x > 0
+            && offset.y > 0
+            && offset.x < eye.footprint.width() as i32 - 1
+            && offset.y < eye.footprint.height() as i32 - 1;
+        // the eye may be able to see over the cells around it
+        interior
+            && eye.height.is_none()
+            && Direction::all().all(|direction| {
+                let coord = eye.coord + offset + direction.coord();
+                self.in_bounds(coord)
+                    && CardinalDirection::all().all(|entering| {
+                        self.initial_visibility
+                            .attenuate(self.get_opacity(self.canonical(coord), entering))
+                            .is_none()
+                    })
+            })
+    }
+
+    fn canonical(&self, coord: Coord) -> Coord {
+        Coord::new(
+            self.x_edges.canonical(coord.x),
+            self.y_edges.canonical(coord.y),
+        )
+    }
+
+    /// The number of cells beyond the given coord before the edge of the grid in the
+    /// given direction
+    fn space(&self, coord: Coord, direction: CardinalDirection) -> i32 {
+        let step = direction.coord();
+        if step.x != 0 {
+            self.x_edges.space(coord.x, step.x)
+        } else {
+            self.y_edges.space(coord.y, step.y)
+        }
+    }
+
+    /// The thin wall between two adjacent cells, as a cell within the grid and the edge of
+    /// that cell, or `None` if the edge is off the grid
+    fn wall_between(&self, a: Coord, b: Coord) -> Option<(Coord, CellEdge)> {
+        let delta = b - a;
+        let (coord, edge) = match (delta.x, delta.y) {
+            (1, 0) => (b, CellEdge::West),
+            (-1, 0) => (a, CellEdge::West),
+            (0, 1) => (b, CellEdge::North),
+            _ => (a, CellEdge::North),
+        };
+        if self.in_bounds(coord) {
+            Some((self.canonical(coord), edge))
+        } else {
+            None
+        }
+    }
+
+    fn get_wall_opacity(&self, wall: Option<(Coord, CellEdge)>) -> Option<I::Opacity> {
+        let (coord, edge) = wall?;
+        self.input_grid.get_edge_opacity(self.grid, coord, edge)
+    }
+
+    /// The distance in cells from the eye to the centre of the cell at `coord`
+    fn distance(&self, coord: Coord) -> f64 {
+        let delta = coord - self.centre;
+        let x = delta.x as i64 * SUB_CELLS - self.eye_offset.x as i64;
+        let y = delta.y as i64 * SUB_CELLS - self.eye_offset.y as i64;
+        (x as f64).hypot(y as f64) / SUB_CELLS as f64
+    }
+
+    /// The visibility with which a cell seen with the given visibility is reported, or
+    /// `None` if the visibility has fallen off to nothing by the time it reaches the cell
+    fn reported_visibility(
+        &self,
+        coord: Coord,
+        visibility: Visibility,
+    ) -> Option<Visibility> {
+        match &self.falloff {
+            None => Some(visibility),
+            Some(falloff) => falloff(visibility, self.distance(coord)),
+        }
+    }
+
+    /// The gradient of the highest point of the top of a cell, or `None` if the cell is
+    /// infinitely tall
+    fn get_top(
+        &self,
+        coord: Coord,
+        front_gradient_depth: i64,
+        back_gradient_depth: i64,
+    ) -> Option<VerticalGradient> {
+        let eye_height = self.eye_height?;
+        let height = self.input_grid.get_height(self.grid, coord)?;
+        let rise = height as i64 - eye_height as i64;
+        let run = if rise > 0 {
+            front_gradient_depth
+        } else {
+            back_gradient_depth
+        };
+        if run <= 0 {
+            // the near edge of the cell is level with the eye, so if its top is higher
+            // than the eye then nothing can be seen over it
+            return None;
+        }
+        Some(VerticalGradient { rise, run })
     }
 }
 
@@ -134,16 +1726,19 @@ struct ScanParams<Visibility> {
     min_inclusive: bool,
     depth: i32,
     visibility: Visibility,
+    /// Only cells which rise above this gradient are visible
+    horizon: Option<VerticalGradient>,
 }
 
 impl<Visibility> ScanParams<Visibility> {
-    fn octant_base(visibility: Visibility) -> Self {
+    fn octant_base(wedge: Wedge, visibility: Visibility) -> Self {
         Self {
-            min_gradient: Gradient::new(0, 1),
-            max_gradient: Gradient::new(1, 1),
+            min_gradient: wedge.min_gradient,
+            max_gradient: wedge.max_gradient,
             min_inclusive: true,
-            depth: 1,
+            depth: 0,
             visibility,
+            horizon: None,
         }
     }
 }
@@ -154,23 +1749,65 @@ struct CornerInfo<Visibility> {
     visibility: Visibility,
 }
 
-fn scan<I, Visibility, O, VisDist, F>(
+/// A cell seen by more than one scan of the current depth, such as the cell along the
+/// diagonal between a pair of octants, or a cell shared by neighbouring sections of a row,
+/// whose views are combined before it's reported
+struct Corner<Visibility> {
+    bitmap: DirectionBitmap,
+    seen: Option<(Coord, Visibility)>,
+}
+
+impl<Visibility: MergeVisibility> Corner<Visibility> {
+    fn new() -> Self {
+        Self {
+            bitmap: DirectionBitmap::empty(),
+            seen: None,
+        }
+    }
+
+    fn add(&mut self, corner: CornerInfo<Visibility>) {
+        self.bitmap |= corner.bitmap;
+        let visibility = match self.seen {
+            Some((_, visibility)) => visibility.most_visible(corner.visibility),
+            None => corner.visibility,
+        };
+        self.seen = Some((corner.coord, visibility));
+    }
+
+    fn coord(&self) -> Option<Coord> {
+        self.seen.map(|(coord, _)| coord)
+    }
+
+    /// The combined view of the cell, if it was seen at all, resetting the corner for the
+    /// next depth
+    fn take(&mut self) -> Option<(Coord, DirectionBitmap, Visibility)> {
+        let corner = mem::replace(self, Self::new());
+        corner.seen.map(|(coord, visibility)| {
+            (coord, without_redundant_corners(corner.bitmap), visibility)
+        })
+    }
+}
+
+/// Scan a section of a row, reporting the visible cells, and adding the sections of the
+/// next row which can be seen through it to `next`. Stops as soon as `f` returns `Break`.
+/// The thin walls which can be seen are added to `walls` if they are being reported. The
+/// first and last cells of the section are combined into `shared`, as they may also be
+/// seen by the neighbouring sections of the row.
+fn scan<I, Visibility, O, VisDist, F, R>(
     octant: &O,
     next: &mut Vec<ScanParams<Visibility>>,
+    walls: &mut Vec<(Coord, CellEdge, Visibility)>,
+    shared: &mut Corner<Visibility>,
     params: ScanParams<Visibility>,
     static_params: &StaticParams<I, Visibility, VisDist>,
     f: &mut F,
-) -> Option<CornerInfo<Visibility>>
+) -> ControlFlow<R, Option<CornerInfo<Visibility>>>
 where
     I: InputGrid,
     O: Octant,
-    Visibility: Copy
-        + Zero
-        + PartialOrd<I::Opacity>
-        + PartialOrd
-        + Sub<I::Opacity, Output = Visibility>,
+    Visibility: Attenuate<I::Opacity>,
     VisDist: VisionDistance,
-    F: FnMut(Coord, DirectionBitmap, Visibility),
+    F: FnMut(Coord, DirectionBitmap, Visibility) -> ControlFlow<R>,
 {
     let ScanParams {
         mut min_gradient,
@@ -178,58 +1815,77 @@ where
         mut min_inclusive,
         depth,
         visibility,
+        horizon,
     } = params;
 
-    let depth_index =
-        if let Some(depth_index) = octant.depth_index(static_params.centre, depth) {
-            depth_index
-        } else {
-            // depth puts this strip out of bounds within the current octant
-            return None;
-        };
+    if depth > static_params.max_depth {
+        // no cell at this depth or beyond is within the vision distance
+        return ControlFlow::Continue(None);
+    }
+
+    // depth puts this strip out of bounds within the current octant
+    if depth > static_params.space(static_params.centre, octant.depth_direction()) {
+        return ControlFlow::Continue(None);
+    }
 
-    // the distance in half-cells between the centre of the row being scanned
-    // and the centre of the eye
-    let mid_gradient_depth = depth * 2;
-    let front_gradient_depth = mid_gradient_depth - 1;
-    let back_gradient_depth = mid_gradient_depth + 1;
+    // the position of the eye relative to the centre of its cell, in sub-cells
+    let (offset_lateral, offset_depth) =
+        octant.lateral_and_depth(static_params.eye_offset);
+    let (offset_lateral, offset_depth) = (offset_lateral as i64, offset_depth as i64);
 
-    let effective_gradient_depth = mid_gradient_depth;
+    // the distance in sub-cells between the centre of the row being scanned
+    // and the eye
+    let mid_gradient_depth = depth as i64 * SUB_CELLS - offset_depth;
+    let front_gradient_depth = mid_gradient_depth - HALF_CELL;
+    let back_gradient_depth = mid_gradient_depth + HALF_CELL;
+
+    if let Some(falloff) = &static_params.falloff {
+        // Every cell in the row is at least as far from the eye as the centre of the row
+        // is deep, so if nothing would be visible there then nothing beyond is visible
+        // either.
+        let distance = cmp::max(mid_gradient_depth, 0) as f64 / SUB_CELLS as f64;
+        if falloff(visibility, distance).is_none() {
+            return ControlFlow::Continue(None);
+        }
+    }
+
+    let effective_gradient_depth = effective_gradient_depth(depth, offset_depth);
 
     let lateral_min = {
-        // We're interested in the width in half-cells of the right triangle which is
-        // similar to min_gradient, and whose depth is effective_gradient_depth. Since the
-        // eye is in the centre of a cell, the lateral min index will be half of (this
-        // width + 1).  It's incremented to account for the eye being in the centre of its
-        // cell (ie. 1 half-cell to the right of the left edge of the cell.  It's halved
-        // because the computed width will be in half-cells.
+        // We're interested in the width in sub-cells of the right triangle which is
+        // similar to min_gradient, and whose depth is effective_gradient_depth. The
+        // lateral min index is the index of the cell containing the far corner of this
+        // triangle.
         //
         // Similar triangles:
-        // width_half_cells / effective_gradient_depth =
+        // width_sub_cells / effective_gradient_depth =
         // min_gradient.lateral / min_gradient.depth
         //
         // Thus:
-        // width_half_cells = (min_gradient.lateral * effective_gradient_depth) /
-        //                    min_gradient.depth
+        // width_sub_cells = (min_gradient.lateral * effective_gradient_depth) /
+        //                   min_gradient.depth
         //
-        // Since the eye is in the centre of a cell:
-        // offset_half_cells = 1 + width_half_cells
-        //                   = 1 + ((min_gradient.lateral * effective_gradient_depth) /
-        //                         min_gradient.depth)
-        //                   = (min_gradient_depth + (min_gradient.lateral *
-        //                      effectivte_gradient_depth)) / min_gradient.depth
+        // The eye is offset_lateral sub-cells from the centre of its cell, which is
+        // HALF_CELL sub-cells from the left edge of the cell, so the offset from the left
+        // edge of the eye's cell is:
+        // offset_sub_cells = HALF_CELL + offset_lateral + width_sub_cells
+        //                  = ((min_gradient.depth * (HALF_CELL + offset_lateral)) +
+        //                     (min_gradient.lateral * effective_gradient_depth)) /
+        //                    min_gradient.depth
         //
         // So the offset in cells is:
-        // offset_cells = offset_half_cells / 2
-        //              = (min_gradient_depth +
-        //                      (min_gradient.lateral * effective_gradient_depth)) /
-        //                (min_gradient.depth * 2)
+        // offset_cells = offset_sub_cells / SUB_CELLS
+        //              = ((min_gradient.depth * (HALF_CELL + offset_lateral)) +
+        //                 (min_gradient.lateral * effective_gradient_depth)) /
+        //                (min_gradient.depth * SUB_CELLS)
         //
         // Finally, if this section is not min_inclusive, we skip the first index,
         // increment the result by 1.
-        ((min_gradient.depth + (min_gradient.lateral * effective_gradient_depth))
-            / (min_gradient.depth * 2))
-            + ((!min_inclusive) as i32)
+        let lateral_min = ((min_gradient.depth * (HALF_CELL + offset_lateral))
+            + (min_gradient.lateral * effective_gradient_depth))
+            .div_euclid(min_gradient.depth * SUB_CELLS)
+            + ((!min_inclusive) as i64);
+        cmp::max(lateral_min, 0)
     };
 
     let lateral_max = {
@@ -238,70 +1894,301 @@ where
         // exactly on a left corner of a cell, that cell is not included in the scanned
         // range - there is no max_inclusive analog of min_inclusive. All ranges are
         // effectively max inclusive, so there is no need to change the result accordingly
-        (max_gradient.depth + (max_gradient.lateral * effective_gradient_depth) - 1)
-            / (max_gradient.depth * 2)
+        ((max_gradient.depth * (HALF_CELL + offset_lateral))
+            + (max_gradient.lateral * effective_gradient_depth)
+            - 1)
+        .div_euclid(max_gradient.depth * SUB_CELLS)
+    };
+
+    // prevent scanning off the edge of the octant
+    let lateral_max = cmp::min(
+        lateral_max,
+        static_params.space(static_params.centre, octant.lateral_direction()) as i64,
+    );
+
+    // the sections of the row which would be scanned if the coverage didn't limit it
+    let (natural_lateral_min, natural_lateral_max) = (lateral_min, lateral_max);
+
+    let (lateral_min, lateral_max) = match static_params.lateral_window(
+        octant,
+        effective_gradient_depth,
+        (offset_lateral, offset_depth),
+    ) {
+        Some((window_min, window_max)) => (
+            cmp::max(lateral_min, window_min),
+            cmp::min(lateral_max, window_max),
+        ),
+        None => (lateral_min, lateral_max),
+    };
+    let reported_wedges = static_params.reported_wedges(octant);
+    let reported_lateral = |lateral_index: i32| match reported_wedges {
+        Some(wedges) => wedges.iter().flatten().any(|wedge| {
+            let (min, max) =
+                wedge.lateral_range(effective_gradient_depth, offset_lateral);
+            (min..=max).contains(&(lateral_index as i64))
+        }),
+        None => true,
+    };
+    let reported_gradients = |lo: Gradient, hi: Gradient| match reported_wedges {
+        Some(wedges) => wedges.iter().flatten().any(|wedge| {
+            lo.clamp(wedge.min_gradient, wedge.max_gradient)
+                .is_below(hi.clamp(wedge.min_gradient, wedge.max_gradient))
+        }),
+        None => true,
+    };
+    let starts_naturally = lateral_min == natural_lateral_min;
+    let ends_naturally = lateral_max == natural_lateral_max;
+    // gradients within an octant are at most 1, so these are within a cell of the depth
+    let (lateral_min, lateral_max) = (lateral_min as i32, lateral_max as i32);
+
+    let symmetric = static_params.mode == Mode::Symmetric;
+
+    // what can be seen by lines of sight entering the row within this section
+    let section = Passage {
+        visibility,
+        horizon,
+        blocked: false,
+    };
+    let section_min_gradient = min_gradient;
+    // what can be seen through the part of the row from min_gradient to the current cell
+    let mut piece = section;
+    // what can be seen through the previous cell, ignoring any wall behind it
+    let mut prev_body = section;
+    let mut corner = None;
+
+    // What can be seen by lines of sight entering the row which pass through a thin wall.
+    // Those passing through anything else in the row as well see as little as they would
+    // through the more obstructive of the wall and the other thing.
+    let wall_passage = |wall| {
+        static_params
+            .get_wall_opacity(wall)
+            .map(|opacity| section.through(Some(opacity)))
+    };
+    let through_wall =
+        |passage: Passage<Visibility>, wall: Option<Passage<Visibility>>| match wall {
+            Some(wall) => passage.most_obstructed(wall),
+            None => passage,
+        };
+    // Report a thin wall if any line of sight between lo and hi reaches it
+    let mut report_wall = |wall: Option<(Coord, CellEdge)>,
+                           passage: Passage<Visibility>,
+                           lo: Gradient,
+                           hi: Gradient,
+                           near: Coord| {
+        let (wall_coord, edge) = match wall {
+            Some(wall) if static_params.report_walls => wall,
+            _ => return,
+        };
+        if static_params.get_wall_opacity(wall).is_none() {
+            return;
+        }
+        let lo = lo.clamp(section_min_gradient, max_gradient);
+        let hi = hi.clamp(section_min_gradient, max_gradient);
+        if passage.blocked
+            || !lo.is_below(hi)
+            || !reported_gradients(lo, hi)
+            || !static_params
+                .vision_distance
+                .in_range(near - static_params.centre)
+        {
+            return;
+        }
+        if let Some(visibility) =
+            static_params.reported_visibility(near, passage.visibility)
+        {
+            walls.push((wall_coord, edge, visibility));
+        }
     };
 
-    // prevent scanning off the edge of the octant
-    let lateral_max = cmp::min(lateral_max, octant.lateral_max(static_params.centre));
-
-    let mut prev_visibility = Zero::zero();
-    let mut prev_opaque = false;
-
     for lateral_index in lateral_min..=lateral_max {
-        let coord = octant.make_coord(static_params.centre, lateral_index, depth_index);
-        if coord.x < 0
-            || coord.x >= static_params.width
-            || coord.y < 0
-            || coord.y >= static_params.height
-        {
+        let unwrapped_coord =
+            octant.make_coord(static_params.centre, lateral_index, depth);
+        if !static_params.in_bounds(unwrapped_coord) {
             break;
         };
+        let coord = static_params.canonical(unwrapped_coord);
 
-        let opacity = static_params.get_opacity(coord);
+        let (cur_visibility, cur_opaque) = if depth == 0 && lateral_index == 0 {
+            // the eye's own cell doesn't obstruct the view
+            (visibility, false)
+        } else {
+            let opacity = static_params.get_opacity(coord, octant.depth_direction());
+            match visibility.attenuate(opacity) {
+                Some(visibility) => (visibility, false),
+                None => (visibility, true),
+            }
+        };
+
+        let top = static_params.get_top(coord, front_gradient_depth, back_gradient_depth);
+
+        // An opaque cell with a top only obstructs the view below its top, so the view
+        // beyond it is unchanged apart from the horizon.
+        let (cur_visibility, cur_horizon, cur_blocked) = match top {
+            Some(top) if cur_opaque => {
+                let horizon = match horizon {
+                    Some(horizon) if !top.is_above(horizon) => horizon,
+                    _ => top,
+                };
+                (visibility, Some(horizon), false)
+            }
+            _ => (cur_visibility, horizon, cur_opaque),
+        };
+        let body = Passage {
+            visibility: cur_visibility,
+            horizon: cur_horizon,
+            blocked: cur_blocked,
+        };
 
-        // check if cell is in visible range
-        let in_range = static_params
-            .vision_distance
-            .in_range(coord - static_params.centre);
+        // lines of sight which leave the row through the back of the cell pass through
+        // any wall there
+        let back_wall = static_params.wall_between(
+            unwrapped_coord,
+            octant.make_coord(static_params.centre, lateral_index, depth + 1),
+        );
+        let exit = body.through(static_params.get_wall_opacity(back_wall));
 
-        let gradient_lateral = lateral_index * 2 - 1;
+        let gradient_lateral =
+            lateral_index as i64 * SUB_CELLS - HALF_CELL - offset_lateral;
         let mut direction_bitmap = DirectionBitmap::empty();
 
-        let (cur_visibility, cur_opaque) = if visibility > opacity {
-            (visibility - opacity, false)
+        // Lines of sight between these gradients cross from the previous cell into this
+        // one within the row. If the near end of the edge between them is level with the
+        // eye, every line beyond the back gradient crosses it.
+        let crossing_start = Gradient::new(gradient_lateral, back_gradient_depth);
+        let crossing_end = if front_gradient_depth > 0 {
+            Gradient::new(gradient_lateral, front_gradient_depth)
+        } else {
+            max_gradient
+        };
+
+        // The thin wall between the previous cell and this one can only be crossed if
+        // it's beyond the eye. Before the start of a limited section, it's unknown whether
+        // the wall can be reached.
+        let prev_coord =
+            octant.make_coord(static_params.centre, lateral_index - 1, depth);
+        let lateral_wall = if gradient_lateral > 0
+            && (lateral_index != lateral_min || starts_naturally)
+        {
+            static_params.wall_between(prev_coord, unwrapped_coord)
         } else {
-            (Zero::zero(), true)
+            None
         };
+        let lateral_wall_passage = wall_passage(lateral_wall);
+        let lateral_wall_blocks = lateral_wall_passage.is_some_and(|wall| wall.blocked);
 
         // handle changes in opacity
-        if lateral_index != lateral_min && cur_visibility != prev_visibility {
-            let gradient_depth = if cur_visibility < prev_visibility {
-                // getting more opaque
-                back_gradient_depth
-            } else {
-                // getting less opaque
-                front_gradient_depth
-            };
-            let gradient = Gradient::new(gradient_lateral, gradient_depth);
-            if !prev_opaque {
-                // see beyond the previous section unless it's opaque
-                next.push(ScanParams {
-                    min_gradient,
-                    max_gradient: gradient,
-                    min_inclusive,
-                    depth: depth + 1,
-                    visibility: prev_visibility,
-                });
+        if lateral_index == lateral_min {
+            // lines of sight at the start of the section may cross the wall before the cell
+            let crossing = through_wall(exit, lateral_wall_passage);
+            if crossing.differs(exit) && min_gradient.is_below(crossing_end) {
+                let gradient = crossing_end.clamp(min_gradient, max_gradient);
+                split_section(
+                    next,
+                    &mut min_gradient,
+                    &mut min_inclusive,
+                    depth,
+                    crossing,
+                    gradient,
+                    symmetric,
+                );
+            }
+        } else {
+            let crossing =
+                through_wall(prev_body.most_obstructed(exit), lateral_wall_passage);
+            let ends_piece = crossing.differs(piece);
+            let starts_exit = crossing.differs(exit);
+            match (ends_piece, starts_exit) {
+                (true, true) if !symmetric || lateral_wall_passage.is_some() => {
+                    // Less can be seen by crossing between the cells than through either
+                    // of them, as there is a wall between them, or as each obstructs
+                    // something the other doesn't.
+                    let gradient = crossing_start.clamp(min_gradient, max_gradient);
+                    split_section(
+                        next,
+                        &mut min_gradient,
+                        &mut min_inclusive,
+                        depth,
+                        piece,
+                        gradient,
+                        symmetric,
+                    );
+                    let gradient = crossing_end.clamp(min_gradient, max_gradient);
+                    split_section(
+                        next,
+                        &mut min_gradient,
+                        &mut min_inclusive,
+                        depth,
+                        crossing,
+                        gradient,
+                        symmetric,
+                    );
+                }
+                (true, _) | (_, true) => {
+                    // Lines of sight crossing between the cells see as much as those
+                    // through the more obstructed of them, which is more opaque or has the
+                    // higher horizon.
+                    let gradient_depth = if symmetric {
+                        // walls are beveled, so the transition is at the centre of the row
+                        mid_gradient_depth
+                    } else if ends_piece {
+                        // getting more obstructed
+                        back_gradient_depth
+                    } else {
+                        // getting less obstructed
+                        front_gradient_depth
+                    };
+                    let gradient = Gradient::new(gradient_lateral, gradient_depth)
+                        .clamp(min_gradient, max_gradient);
+                    split_section(
+                        next,
+                        &mut min_gradient,
+                        &mut min_inclusive,
+                        depth,
+                        piece,
+                        gradient,
+                        symmetric,
+                    );
+                }
+                (false, false) => (),
             }
-            min_gradient = gradient;
-            min_inclusive = false;
-            // If the current cell is opaque, then the previous cell was not opaque and so
-            // we can see the across edge through the previous cell.
-            // If the current cell is transparent, we can see the entire cell (including
-            // the across edge), so setting it again here doesn't hurt.
-            direction_bitmap |= octant.across_bitmap();
+            if ends_piece || starts_exit {
+                // If the current cell is opaque, then the previous cell was not opaque and
+                // so we can see the across edge through the previous cell.
+                // If the current cell is transparent, we can see the entire cell
+                // (including the across edge), so setting it again here doesn't hurt.
+                direction_bitmap |= octant.across_bitmap();
+            }
+        }
+        piece = exit;
+
+        report_wall(
+            lateral_wall,
+            if lateral_index == lateral_min {
+                section
+            } else {
+                prev_body
+            },
+            crossing_start,
+            crossing_end,
+            prev_coord,
+        );
+        report_wall(
+            back_wall,
+            body,
+            if lateral_wall_blocks || (lateral_index != lateral_min && prev_body.blocked)
+            {
+                crossing_end
+            } else {
+                crossing_start
+            },
+            Gradient::new(gradient_lateral + SUB_CELLS, back_gradient_depth),
+            unwrapped_coord,
+        );
+        if lateral_wall_blocks && !crossing_end.is_below(max_gradient) {
+            // the rest of the section is behind the wall
+            break;
         }
+
         if cur_opaque {
             // check if we can actually see the facing side
             if max_gradient.lateral * front_gradient_depth
@@ -316,62 +2203,255 @@ where
             direction_bitmap |= DirectionBitmap::all();
         };
 
-        // handle final cell
+        // When the eye isn't in the centre of its cell, cells in its own row, and cells
+        // just beyond the diagonal edge of the octant, can obstruct the view. These cells
+        // are reported by other octants.
+        let in_octant = depth > 0 && lateral_index <= depth;
+
+        // In symmetric mode, transparent cells are only visible if their centre is within
+        // the current section.
+        let seen = in_octant
+            && above_horizon(top, horizon)
+            && static_params
+                .vision_distance
+                .in_range(unwrapped_coord - static_params.centre)
+            && reported_lateral(lateral_index)
+            && (!symmetric || cur_opaque || {
+                let centre_lateral = lateral_index as i64 * SUB_CELLS - offset_lateral;
+                min_gradient.lateral * mid_gradient_depth
+                    <= centre_lateral * min_gradient.depth
+                    && centre_lateral * max_gradient.depth
+                        <= max_gradient.lateral * mid_gradient_depth
+            });
+
         if lateral_index == lateral_max {
-            if !cur_opaque && min_gradient != max_gradient {
+            if ends_naturally {
+                // Lines of sight at the end of the section may cross into the next cell
+                // and leave the row through its back.
+                let beyond =
+                    octant.make_coord(static_params.centre, lateral_index + 1, depth);
+                let wall = static_params.wall_between(unwrapped_coord, beyond);
+                let beyond_back_wall = static_params.wall_between(
+                    beyond,
+                    octant.make_coord(static_params.centre, lateral_index + 1, depth + 1),
+                );
+                let crossing = through_wall(
+                    through_wall(body, wall_passage(wall)),
+                    wall_passage(beyond_back_wall),
+                );
+                let crossing_start =
+                    Gradient::new(gradient_lateral + SUB_CELLS, back_gradient_depth);
+                let crossing_end = if front_gradient_depth > 0 {
+                    Gradient::new(gradient_lateral + SUB_CELLS, front_gradient_depth)
+                } else {
+                    max_gradient
+                };
+                if crossing.differs(piece) && crossing_start.is_below(max_gradient) {
+                    let gradient = crossing_start.clamp(min_gradient, max_gradient);
+                    split_section(
+                        next,
+                        &mut min_gradient,
+                        &mut min_inclusive,
+                        depth,
+                        piece,
+                        gradient,
+                        symmetric,
+                    );
+                    piece = crossing;
+                }
+                report_wall(wall, body, crossing_start, crossing_end, unwrapped_coord);
+            }
+
+            // handle final cell
+            if !piece.blocked && min_gradient != max_gradient {
                 // see beyond the current section
                 next.push(ScanParams {
                     min_gradient,
                     max_gradient,
                     min_inclusive,
                     depth: depth + 1,
-                    visibility: cur_visibility,
+                    visibility: piece.visibility,
+                    horizon: piece.horizon,
                 });
             }
-            if in_range && lateral_index == depth {
-                // Intentionally don't invoke the callback on the final cell of
-                // the scan, if it's along the diagonal between two octants.
-                // The result of both octant scans is required to determine the
-                // visibility of this cell. It is handled in
+        }
+
+        let reported_visibility = if seen {
+            static_params.reported_visibility(unwrapped_coord, visibility)
+        } else {
+            None
+        };
+        if let Some(visibility) = reported_visibility {
+            if lateral_index == depth {
+                // Intentionally don't invoke the callback on the cell along the diagonal
+                // between two octants. The result of both octant scans is required to
+                // determine the visibility of this cell. It is handled in
                 // Context::observe_octant.
-                return Some(CornerInfo {
+                corner = Some(CornerInfo {
                     bitmap: direction_bitmap,
                     coord,
                     visibility,
                 });
+            } else if lateral_index == lateral_min || lateral_index == lateral_max {
+                // The previous section of the row may have ended in this cell, or the next
+                // may start in it, so it's reported once the row has been scanned, or as
+                // soon as another section's first or last cell is seen.
+                if octant.should_see(lateral_index) {
+                    if shared.coord().is_some_and(|shared| shared != coord) {
+                        if let Some((coord, bitmap, visibility)) = shared.take() {
+                            f(coord, bitmap, visibility)?;
+                        }
+                    }
+                    shared.add(CornerInfo {
+                        bitmap: direction_bitmap,
+                        coord,
+                        visibility,
+                    });
+                }
+            } else if octant.should_see(lateral_index) {
+                f(coord, direction_bitmap, visibility)?;
             }
         }
 
-        if in_range && octant.should_see(lateral_index) {
-            f(coord, direction_bitmap, visibility);
+        prev_body = body;
+    }
+
+    ControlFlow::Continue(corner)
+}
+
+/// The depth beyond which no cells are scanned
+fn max_depth<V: VisionDistance>(
+    vision_distance: V,
+    x_edges: Edges,
+    y_edges: Edges,
+) -> i32 {
+    match vision_distance.max_depth() {
+        Some(max_depth) => cmp::min(max_depth, i32::MAX as u32) as i32,
+        None if x_edges.is_wrapped() || y_edges.is_wrapped() => {
+            // Without a limit, scanning would continue around a wrapping grid forever.
+            // Stop once every cell could have been seen.
+            cmp::max(x_edges.size(), y_edges.size()).unwrap_or(0)
+        }
+        None if x_edges.size().is_none() || y_edges.size().is_none() => {
+            // Only the vision distance limits the scan, so stop before the first square
+            // of cells around the eye none of which are in range.
+            let square_in_range = |depth: i32| {
+                (-depth..=depth).any(|i| {
+                    [(i, -depth), (i, depth), (-depth, i), (depth, i)]
+                        .into_iter()
+                        .any(|(x, y)| vision_distance.in_range(Coord::new(x, y)))
+                })
+            };
+            (1..i32::MAX)
+                .find(|&depth| !square_in_range(depth))
+                .map_or(i32::MAX, |depth| depth - 1)
         }
+        None => i32::MAX,
+    }
+}
+
+/// The depth beyond which no cells are scanned in the given grid
+pub(crate) fn grid_max_depth<I: InputGrid, V: VisionDistance>(
+    input_grid: &I,
+    grid: &I::Grid,
+    vision_distance: V,
+) -> i32 {
+    let (x_edges, y_edges) = Edges::for_grid(input_grid, grid);
+    max_depth(vision_distance, x_edges, y_edges)
+}
 
-        prev_visibility = cur_visibility;
-        prev_opaque = cur_opaque;
+/// Combine the edges of a cell seen by separate scans
+fn without_redundant_corners(bitmap: DirectionBitmap) -> DirectionBitmap {
+    if !(bitmap.is_full() || (bitmap & DirectionBitmap::all_cardinal()).is_empty()) {
+        // if one of the scans saw a corner only but the other saw
+        // the entire edge, just keep the edge.
+        bitmap & DirectionBitmap::all_cardinal()
+    } else {
+        bitmap
     }
+}
 
-    None
+/// Report each cell once, combining the results of scans which saw the same cell. The
+/// vector is left empty.
+pub(crate) fn report_merged<Visibility, F, R>(
+    cells: &mut Vec<(Coord, DirectionBitmap, Visibility)>,
+    f: &mut F,
+) -> ControlFlow<R>
+where
+    Visibility: MergeVisibility,
+    F: FnMut(Coord, DirectionBitmap, Visibility) -> ControlFlow<R>,
+{
+    cells.sort_unstable_by_key(|&(coord, _, _)| coord);
+    let mut cells = cells.drain(..);
+    if let Some((mut coord, mut bitmap, mut visibility)) = cells.next() {
+        for (next_coord, next_bitmap, next_visibility) in cells {
+            if next_coord == coord {
+                bitmap |= next_bitmap;
+                visibility = visibility.most_visible(next_visibility);
+            } else {
+                f(coord, without_redundant_corners(bitmap), visibility)?;
+                coord = next_coord;
+                bitmap = next_bitmap;
+                visibility = next_visibility;
+            }
+        }
+        f(coord, without_redundant_corners(bitmap), visibility)?;
+    }
+    ControlFlow::Continue(())
 }
 
 #[derive(Clone, Debug)]
 pub struct Context<Visibility> {
+    mode: Mode,
+    merge: Vec<(Coord, DirectionBitmap, Visibility)>,
     queue_a: Vec<ScanParams<Visibility>>,
     queue_a_swap: Vec<ScanParams<Visibility>>,
     queue_b: Vec<ScanParams<Visibility>>,
     queue_b_swap: Vec<ScanParams<Visibility>>,
+    pub(crate) walls: Vec<(Coord, CellEdge, Visibility)>,
+    #[cfg(feature = "rayon")]
+    pub(crate) octant_pairs: Vec<crate::parallel::OctantPair<Visibility>>,
 }
 
 impl<Visibility> Default for Context<Visibility> {
     fn default() -> Self {
+        Self::new(Mode::default())
+    }
+}
+
+impl<Visibility> Context<Visibility> {
+    /// A context which scans in the given mode.
+    ///
+    /// The mode is not serialized: a deserialized context is always in the default
+    /// (asymmetric) mode, so call `set_mode` again after deserializing.
+    pub fn new(mode: Mode) -> Self {
         Self {
+            mode,
+            merge: Vec::new(),
             queue_a: Vec::new(),
             queue_a_swap: Vec::new(),
             queue_b: Vec::new(),
             queue_b_swap: Vec::new(),
+            walls: Vec::new(),
+            #[cfg(feature = "rayon")]
+            octant_pairs: Vec::new(),
         }
     }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Change the mode of subsequent scans. Like the mode passed to `new`, this is not
+    /// kept when the context is serialized.
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
 }
 
+// A context holds no state between scans, so nothing is serialized, not even its mode.
+// This keeps the serialized form the same as before there were modes. Deserialized
+// contexts are in the default mode.
 #[cfg(feature = "serialize")]
 impl<Visibility> Serialize for Context<Visibility> {
     fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
@@ -388,123 +2468,653 @@ impl<'a, Visibility> Deserialize<'a> for Context<Visibility> {
 }
 
 impl<Visibility> Context<Visibility> {
-    fn observe_octant<I, A, B, VisDist, F>(
+    /// Scan a pair of octants which share a diagonal, stopping as soon as `f` returns
+    /// `Break`
+    pub(crate) fn observe_octant<I, A, B, VisDist, F, R>(
         &mut self,
         octant_a: A,
         octant_b: B,
         static_params: &StaticParams<I, Visibility, VisDist>,
         f: &mut F,
-    ) where
+    ) -> ControlFlow<R>
+    where
         I: InputGrid,
-        Visibility: Copy
-            + Zero
-            + PartialOrd<I::Opacity>
-            + PartialOrd
-            + Sub<I::Opacity, Output = Visibility>,
+        Visibility: Attenuate<I::Opacity>,
         A: Octant,
         B: Octant,
         VisDist: VisionDistance,
-        F: FnMut(Coord, DirectionBitmap, Visibility),
+        F: FnMut(Coord, DirectionBitmap, Visibility) -> ControlFlow<R>,
     {
-        self.queue_a
-            .push(ScanParams::octant_base(static_params.initial_visibility));
-        self.queue_b
-            .push(ScanParams::octant_base(static_params.initial_visibility));
+        if let Some(wedge) = static_params.octant_wedge(&octant_a) {
+            self.queue_a.push(ScanParams::octant_base(
+                wedge,
+                static_params.initial_visibility,
+            ));
+        }
+        if let Some(wedge) = static_params.octant_wedge(&octant_b) {
+            self.queue_b.push(ScanParams::octant_base(
+                wedge,
+                static_params.initial_visibility,
+            ));
+        }
 
-        loop {
-            let mut corner_bitmap = DirectionBitmap::empty();
-            let mut corner_coord = None;
-            let mut corner_visibility = Zero::zero();
+        let flow = self.scan_octant_pair(&octant_a, &octant_b, static_params, f);
+        if flow.is_break() {
+            // leave the queues empty for the next scan
+            self.queue_a.clear();
+            self.queue_a_swap.clear();
+            self.queue_b.clear();
+            self.queue_b_swap.clear();
+        }
+        flow
+    }
 
+    fn scan_octant_pair<I, A, B, VisDist, F, R>(
+        &mut self,
+        octant_a: &A,
+        octant_b: &B,
+        static_params: &StaticParams<I, Visibility, VisDist>,
+        f: &mut F,
+    ) -> ControlFlow<R>
+    where
+        I: InputGrid,
+        Visibility: Attenuate<I::Opacity>,
+        A: Octant,
+        B: Octant,
+        VisDist: VisionDistance,
+        F: FnMut(Coord, DirectionBitmap, Visibility) -> ControlFlow<R>,
+    {
+        let mut corner = Corner::new();
+        let mut shared = Corner::new();
+        loop {
             for params in self.queue_a.drain(..) {
-                if let Some(corner) =
-                    scan(&octant_a, &mut self.queue_a_swap, params, static_params, f)
-                {
-                    corner_bitmap |= corner.bitmap;
-                    corner_coord = Some(corner.coord);
-                    if corner.visibility > corner_visibility {
-                        corner_visibility = corner.visibility;
-                    }
+                if let Some(info) = scan(
+                    octant_a,
+                    &mut self.queue_a_swap,
+                    &mut self.walls,
+                    &mut shared,
+                    params,
+                    static_params,
+                    f,
+                )? {
+                    corner.add(info);
                 }
             }
 
             for params in self.queue_b.drain(..) {
-                if let Some(corner) =
-                    scan(&octant_b, &mut self.queue_b_swap, params, static_params, f)
-                {
-                    corner_bitmap |= corner.bitmap;
-                    corner_coord = Some(corner.coord);
-                    if corner.visibility > corner_visibility {
-                        corner_visibility = corner.visibility;
-                    }
+                if let Some(info) = scan(
+                    octant_b,
+                    &mut self.queue_b_swap,
+                    &mut self.walls,
+                    &mut shared,
+                    params,
+                    static_params,
+                    f,
+                )? {
+                    corner.add(info);
                 }
             }
 
-            if let Some(corner_coord) = corner_coord {
-                if !(corner_bitmap.is_full()
-                    || (corner_bitmap & DirectionBitmap::all_cardinal()).is_empty())
-                {
-                    // if one of the scans saw a corner only but the other saw
-                    // the entire edge, just keep the edge.
-                    corner_bitmap &= DirectionBitmap::all_cardinal();
+            for cell in [&mut corner, &mut shared] {
+                if let Some((coord, bitmap, visibility)) = cell.take() {
+                    f(coord, bitmap, visibility)?;
                 }
-                f(corner_coord, corner_bitmap, corner_visibility);
             }
 
             if self.queue_a_swap.is_empty() && self.queue_b_swap.is_empty() {
-                break;
+                return ControlFlow::Continue(());
             }
             mem::swap(&mut self.queue_a, &mut self.queue_a_swap);
             mem::swap(&mut self.queue_b, &mut self.queue_b_swap);
         }
     }
 
-    pub fn for_each_visible<I, V, F>(
+    /// Call `f` with each cell visible from `view`, which may just be the coord of the
+    /// eye's cell
+    pub fn for_each_visible<W, L, I, V, F>(
         &mut self,
-        coord: Coord,
+        view: W,
         input_grid: &I,
         grid: &I::Grid,
         vision_distance: V,
         initial_visibility: Visibility,
-        mut f: F,
+        f: F,
     ) where
+        W: Into<View<L>>,
+        L: ApplyFalloff<Visibility> + Sync,
         I: InputGrid,
         V: VisionDistance,
         F: FnMut(Coord, DirectionBitmap, Visibility),
-        Visibility: Copy
-            + Zero
-            + PartialOrd<I::Opacity>
-            + PartialOrd
-            + Sub<I::Opacity, Output = Visibility>,
+        Visibility: Attenuate<I::Opacity>,
     {
-        let size = input_grid.size(grid);
-        if coord.is_valid(size) {
-            f(coord, DirectionBitmap::all(), initial_visibility);
-        }
-        let width = size.x() as i32;
-        let height = size.y() as i32;
-        let params: StaticParams<I, _, _> = StaticParams {
-            centre: coord,
+        self.observe(
+            view.into(),
+            Coverage::All,
+            input_grid,
+            grid,
+            vision_distance,
+            initial_visibility,
+            f,
+        );
+    }
+
+    /// Like `for_each_visible`, but scanning stops as soon as `f` returns `Break`, and the
+    /// value it breaks with is returned. In permissive modes, from viewers with a
+    /// footprint, and on grids which wrap around within the vision distance, everything is
+    /// scanned before `f` is first called, as cells seen more than once must be merged.
+    pub fn try_for_each_visible<W, L, I, V, F, R>(
+        &mut self,
+        view: W,
+        input_grid: &I,
+        grid: &I::Grid,
+        vision_distance: V,
+        initial_visibility: Visibility,
+        f: F,
+    ) -> ControlFlow<R>
+    where
+        W: Into<View<L>>,
+        L: ApplyFalloff<Visibility> + Sync,
+        I: InputGrid,
+        V: VisionDistance,
+        F: FnMut(Coord, DirectionBitmap, Visibility) -> ControlFlow<R>,
+        Visibility: Attenuate<I::Opacity>,
+    {
+        self.observe_with(
+            Sequential,
+            view.into(),
+            Coverage::All,
+            input_grid,
+            grid,
             vision_distance,
+            initial_visibility,
+            f,
+        )
+    }
+
+    /// Like `for_each_visible`, but returns an iterator over the visible cells. The scan
+    /// only advances as the iterator is consumed, so adaptors like `find` and `take` stop
+    /// scanning once they have what they need. In permissive modes, from viewers with a
+    /// footprint, and on grids which wrap around within the vision distance, everything is
+    /// scanned before the first cell is returned, as cells seen more than once must be
+    /// merged.
+    pub fn visible_iter<'a, W, L, I, V>(
+        &'a mut self,
+        view: W,
+        input_grid: &'a I,
+        grid: &'a I::Grid,
+        vision_distance: V,
+        initial_visibility: Visibility,
+    ) -> VisibleIter<'a, I, V, Visibility>
+    where
+        W: Into<View<L>>,
+        L: ApplyFalloff<Visibility> + Sync + 'a,
+        I: InputGrid,
+        V: VisionDistance,
+        Visibility: Attenuate<I::Opacity>,
+    {
+        let view = view.into();
+        let params = self.static_params(
+            &view,
+            Coverage::All,
             input_grid,
             grid,
-            width,
-            height,
+            vision_distance,
             initial_visibility,
+        );
+        self.walls.clear();
+        let mut buffer = VecDeque::new();
+        let pair = if params.merges(view.eye) {
+            self.observe(
+                view,
+                Coverage::All,
+                input_grid,
+                grid,
+                vision_distance,
+                initial_visibility,
+                |coord, bitmap, visibility| buffer.push_back((coord, bitmap, visibility)),
+            );
+            VisibleIter::<I, V, Visibility>::NUM_PAIRS
+        } else {
+            let coord = view.eye.coord;
+            if params.in_bounds(coord) {
+                if let Some(visibility) =
+                    params.reported_visibility(coord, initial_visibility)
+                {
+                    buffer.push_back((
+                        params.canonical(coord),
+                        DirectionBitmap::all(),
+                        visibility,
+                    ));
+                }
+            }
+            0
         };
-        self.observe_octant(TopLeft, LeftTop, &params, &mut f);
-        self.observe_octant(RightTop { width }, TopRight { width }, &params, &mut f);
-        self.observe_octant(
-            LeftBottom { height },
-            BottomLeft { height },
-            &params,
-            &mut f,
+        VisibleIter {
+            context: self,
+            params,
+            pair,
+            started: false,
+            taken_a: 0,
+            taken_b: 0,
+            corner: Corner::new(),
+            shared: Corner::new(),
+            buffer,
+        }
+    }
+
+    /// Returns the greatest visibility with which `for_each_visible` would report the cell
+    /// `to` when observing from `from`, or `None` if it would not be reported. Only the
+    /// cells between the eye and `to` are scanned.
+    pub fn is_visible<W, L, I, V>(
+        &mut self,
+        from: W,
+        to: Coord,
+        input_grid: &I,
+        grid: &I::Grid,
+        vision_distance: V,
+        initial_visibility: Visibility,
+    ) -> Option<Visibility>
+    where
+        W: Into<View<L>>,
+        L: ApplyFalloff<Visibility> + Sync,
+        I: InputGrid,
+        V: VisionDistance,
+        Visibility: Attenuate<I::Opacity>,
+    {
+        let view = from.into();
+        let (x_edges, y_edges) = Edges::for_grid(input_grid, grid);
+        let max_depth = max_depth(vision_distance, x_edges, y_edges);
+        let to = Coord::new(x_edges.canonical(to.x), y_edges.canonical(to.y));
+        let mut visibility: Option<Visibility> = None;
+        // the cells scanned depend on where the eye is, so each of a viewer's cells is
+        // observed separately
+        for offset in view.eye.footprint.coord_iter_row_major() {
+            let from = view.eye.coord + offset;
+            let view = View {
+                eye: Eye {
+                    coord: from,
+                    footprint: Size::new(1, 1),
+                    ..view.eye
+                },
+                ..view
+            };
+            let delta = to - from;
+            // on a wrapping grid, the cell may be seen in several directions
+            for delta_y in y_edges.images(delta.y, max_depth) {
+                for delta_x in x_edges.images(delta.x, max_depth) {
+                    let delta = Coord::new(delta_x, delta_y);
+                    if delta != Coord::new(0, 0) && !vision_distance.in_range(delta) {
+                        continue;
+                    }
+                    self.observe(
+                        view,
+                        Coverage::Cell(delta),
+                        input_grid,
+                        grid,
+                        vision_distance,
+                        initial_visibility,
+                        |coord, _, cell_visibility| {
+                            if coord == to {
+                                visibility = Some(match visibility {
+                                    Some(visibility) => {
+                                        visibility.most_visible(cell_visibility)
+                                    }
+                                    None => cell_visibility,
+                                });
+                            }
+                        },
+                    );
+                }
+            }
+        }
+        visibility
+    }
+
+    /// The thin walls seen by the most recent scan of a view with `View::walls`, as the
+    /// cell and the edge of the cell along which each wall lies, and the greatest
+    /// visibility with which it was seen. Each wall appears once, in order of coord.
+    pub fn visible_walls(&mut self) -> &[(Coord, CellEdge, Visibility)]
+    where
+        Visibility: MergeVisibility,
+    {
+        // walls may be seen by several scans, so keep each with the greatest visibility
+        self.walls
+            .sort_unstable_by_key(|&(coord, edge, _)| (coord, edge));
+        self.walls.dedup_by(|&mut (coord, edge, visibility), kept| {
+            let same = (coord, edge) == (kept.0, kept.1);
+            if same {
+                kept.2 = kept.2.most_visible(visibility);
+            }
+            same
+        });
+        &self.walls
+    }
+
+    fn static_params<'a, I, V, L>(
+        &self,
+        view: &View<L>,
+        coverage: Coverage,
+        input_grid: &'a I,
+        grid: &'a I::Grid,
+        vision_distance: V,
+        initial_visibility: Visibility,
+    ) -> StaticParams<'a, I, Visibility, V>
+    where
+        I: InputGrid,
+        V: VisionDistance,
+        L: ApplyFalloff<Visibility> + Sync + 'a,
+    {
+        let (x_edges, y_edges) = Edges::for_grid(input_grid, grid);
+        let mut max_depth = max_depth(vision_distance, x_edges, y_edges);
+        if let Coverage::Cell(delta) = coverage {
+            max_depth = cmp::min(max_depth, cmp::max(delta.x.abs(), delta.y.abs()));
+        }
+        let falloff = view.falloff;
+        StaticParams {
+            centre: view.eye.coord,
+            eye_offset: clamp_eye_offset(view.eye.offset),
+            eye_height: view.eye.height,
+            mode: self.mode,
+            coverage,
+            vision_distance,
+            max_depth,
+            input_grid,
+            grid,
+            x_edges,
+            y_edges,
+            initial_visibility,
+            cone: view.cone,
+            falloff: L::FALLS_OFF.then(|| {
+                Box::new(move |visibility, distance| falloff.apply(visibility, distance))
+                    as FalloffFn<'a, Visibility>
+            }),
+            report_walls: view.walls,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn observe<I, V, L, F>(
+        &mut self,
+        view: View<L>,
+        coverage: Coverage,
+        input_grid: &I,
+        grid: &I::Grid,
+        vision_distance: V,
+        initial_visibility: Visibility,
+        mut f: F,
+    ) where
+        I: InputGrid,
+        V: VisionDistance,
+        L: ApplyFalloff<Visibility> + Sync,
+        F: FnMut(Coord, DirectionBitmap, Visibility),
+        Visibility: Attenuate<I::Opacity>,
+    {
+        let _: ControlFlow<Infallible> = self.observe_with(
+            Sequential,
+            view,
+            coverage,
+            input_grid,
+            grid,
+            vision_distance,
+            initial_visibility,
+            |coord, bitmap, visibility| {
+                f(coord, bitmap, visibility);
+                ControlFlow::Continue(())
+            },
         );
-        self.observe_octant(
-            BottomRight { width, height },
-            RightBottom { width, height },
-            &params,
-            &mut f,
+    }
+
+    /// Scan the octants around the eye, stopping as soon as `f` returns `Break`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn observe_with<I, V, L, F, O, R>(
+        &mut self,
+        octants: O,
+        view: View<L>,
+        coverage: Coverage,
+        input_grid: &I,
+        grid: &I::Grid,
+        vision_distance: V,
+        initial_visibility: Visibility,
+        mut f: F,
+    ) -> ControlFlow<R>
+    where
+        I: InputGrid,
+        V: VisionDistance,
+        L: ApplyFalloff<Visibility> + Sync,
+        F: FnMut(Coord, DirectionBitmap, Visibility) -> ControlFlow<R>,
+        O: ObserveOctants<I, V, Visibility>,
+        Visibility: Attenuate<I::Opacity>,
+    {
+        let eye = view.eye;
+        let mut params = self.static_params(
+            &view,
+            coverage,
+            input_grid,
+            grid,
+            vision_distance,
+            initial_visibility,
         );
+        self.walls.clear();
+        if params.merges(eye) {
+            let mut merge = mem::take(&mut self.merge);
+            for offset in eye.footprint.coord_iter_row_major() {
+                let enclosed = params.enclosed(eye, offset);
+                let coord = eye.coord + offset;
+                params.centre = coord;
+                for eye_offset in self.mode.eye_offsets(eye.offset) {
+                    params.eye_offset = eye_offset;
+                    if params.in_bounds(coord) {
+                        if let Some(visibility) =
+                            params.reported_visibility(coord, initial_visibility)
+                        {
+                            merge.push((
+                                params.canonical(coord),
+                                DirectionBitmap::all(),
+                                visibility,
+                            ));
+                        }
+                    }
+                    if !enclosed {
+                        octants.observe_octants(
+                            self,
+                            &params,
+                            &mut |coord, bitmap, visibility| {
+                                merge.push((coord, bitmap, visibility));
+                                ControlFlow::Continue(())
+                            },
+                        )?;
+                    }
+                }
+            }
+            let flow = report_merged(&mut merge, &mut f);
+            self.merge = merge;
+            flow
+        } else {
+            if params.in_bounds(eye.coord) {
+                if let Some(visibility) =
+                    params.reported_visibility(eye.coord, initial_visibility)
+                {
+                    f(
+                        params.canonical(eye.coord),
+                        DirectionBitmap::all(),
+                        visibility,
+                    )?;
+                }
+            }
+            octants.observe_octants(self, &params, &mut f)
+        }
+    }
+}
+
+/// How the octant pairs around an eye are scanned
+pub(crate) trait ObserveOctants<I: InputGrid, V, Visibility> {
+    fn observe_octants<F, R>(
+        &self,
+        context: &mut Context<Visibility>,
+        params: &StaticParams<I, Visibility, V>,
+        f: &mut F,
+    ) -> ControlFlow<R>
+    where
+        F: FnMut(Coord, DirectionBitmap, Visibility) -> ControlFlow<R>;
+}
+
+/// Scan each pair of octants in turn
+pub(crate) struct Sequential;
+
+impl<I, V, Visibility> ObserveOctants<I, V, Visibility> for Sequential
+where
+    I: InputGrid,
+    V: VisionDistance,
+    Visibility: Attenuate<I::Opacity>,
+{
+    fn observe_octants<F, R>(
+        &self,
+        context: &mut Context<Visibility>,
+        params: &StaticParams<I, Visibility, V>,
+        f: &mut F,
+    ) -> ControlFlow<R>
+    where
+        F: FnMut(Coord, DirectionBitmap, Visibility) -> ControlFlow<R>,
+    {
+        context.observe_octant(TopLeft, LeftTop, params, f)?;
+        context.observe_octant(RightTop, TopRight, params, f)?;
+        context.observe_octant(LeftBottom, BottomLeft, params, f)?;
+        context.observe_octant(BottomRight, RightBottom, params, f)
+    }
+}
+
+/// An iterator over the cells visible from an eye, which scans one section of a row at a
+/// time as it is consumed. Returned by `Context::visible_iter`.
+pub struct VisibleIter<'a, I: InputGrid, V, Visibility> {
+    context: &'a mut Context<Visibility>,
+    params: StaticParams<'a, I, Visibility, V>,
+    /// the index of the pair of octants being scanned, in the order used by `Sequential`
+    pair: usize,
+    /// whether the current pair of octants has started being scanned
+    started: bool,
+    /// the number of sections taken from each queue at the current depth
+    taken_a: usize,
+    taken_b: usize,
+    corner: Corner<Visibility>,
+    shared: Corner<Visibility>,
+    buffer: VecDeque<(Coord, DirectionBitmap, Visibility)>,
+}
+
+impl<I, V, Visibility> VisibleIter<'_, I, V, Visibility>
+where
+    I: InputGrid,
+    V: VisionDistance,
+    Visibility: Attenuate<I::Opacity>,
+{
+    const NUM_PAIRS: usize = 4;
+
+    /// Scan the next section of the current pair of octants. Returns `false` if every
+    /// pair has been scanned.
+    fn step(&mut self) -> bool {
+        match self.pair {
+            0 => self.step_pair(TopLeft, LeftTop),
+            1 => self.step_pair(RightTop, TopRight),
+            2 => self.step_pair(LeftBottom, BottomLeft),
+            3 => self.step_pair(BottomRight, RightBottom),
+            _ => return false,
+        }
+        true
+    }
+
+    fn step_pair<A: Octant, B: Octant>(&mut self, octant_a: A, octant_b: B) {
+        let context = &mut *self.context;
+        let buffer = &mut self.buffer;
+        let mut f = |coord, bitmap, visibility| {
+            buffer.push_back((coord, bitmap, visibility));
+            ControlFlow::<Infallible>::Continue(())
+        };
+        if !self.started {
+            self.started = true;
+            let initial_visibility = self.params.initial_visibility;
+            if let Some(wedge) = self.params.octant_wedge(&octant_a) {
+                context
+                    .queue_a
+                    .push(ScanParams::octant_base(wedge, initial_visibility));
+            }
+            if let Some(wedge) = self.params.octant_wedge(&octant_b) {
+                context
+                    .queue_b
+                    .push(ScanParams::octant_base(wedge, initial_visibility));
+            }
+        } else if let Some(params) = context.queue_a.get(self.taken_a) {
+            self.taken_a += 1;
+            let params = params.clone();
+            if let ControlFlow::Continue(Some(info)) = scan(
+                &octant_a,
+                &mut context.queue_a_swap,
+                &mut context.walls,
+                &mut self.shared,
+                params,
+                &self.params,
+                &mut f,
+            ) {
+                self.corner.add(info);
+            }
+        } else if let Some(params) = context.queue_b.get(self.taken_b) {
+            self.taken_b += 1;
+            let params = params.clone();
+            if let ControlFlow::Continue(Some(info)) = scan(
+                &octant_b,
+                &mut context.queue_b_swap,
+                &mut context.walls,
+                &mut self.shared,
+                params,
+                &self.params,
+                &mut f,
+            ) {
+                self.corner.add(info);
+            }
+        } else {
+            // every section at the current depth has been scanned
+            buffer.extend(self.corner.take());
+            buffer.extend(self.shared.take());
+            context.queue_a.clear();
+            context.queue_b.clear();
+            self.taken_a = 0;
+            self.taken_b = 0;
+            if context.queue_a_swap.is_empty() && context.queue_b_swap.is_empty() {
+                self.pair += 1;
+                self.started = false;
+            } else {
+                mem::swap(&mut context.queue_a, &mut context.queue_a_swap);
+                mem::swap(&mut context.queue_b, &mut context.queue_b_swap);
+            }
+        }
+    }
+}
+
+impl<I, V, Visibility> Iterator for VisibleIter<'_, I, V, Visibility>
+where
+    I: InputGrid,
+    V: VisionDistance,
+    Visibility: Attenuate<I::Opacity>,
+{
+    type Item = (Coord, DirectionBitmap, Visibility);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.buffer.pop_front() {
+                return Some(item);
+            }
+            if !self.step() {
+                return None;
+            }
+        }
+    }
+}
+
+impl<I: InputGrid, V, Visibility> Drop for VisibleIter<'_, I, V, Visibility> {
+    fn drop(&mut self) {
+        // the scan may have been abandoned part way through, leaving sections in the
+        // queues which would otherwise be scanned by the next use of the context
+        self.context.queue_a.clear();
+        self.context.queue_a_swap.clear();
+        self.context.queue_b.clear();
+        self.context.queue_b_swap.clear();
     }
 }
@@ -1,6 +1,9 @@
 use super::*;
 use coord_2d::*;
 use direction::*;
+use std::cmp;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
 
 struct Grid<T> {
     size: Size,
@@ -130,14 +133,15 @@ fn check_output(eye: Coord, output: &TestOutputGrid, expected_output_strings: &[
     }
 }
 
-fn check_scenario_with_vision_distance<VD: VisionDistance>(
+fn check_scenario_with_mode_and_vision_distance<VD: VisionDistance>(
+    mode: Mode,
     vision_distance: VD,
     input_strs: &[&str],
     expected_output: &[&str],
 ) {
     let (input, eye) = input_from_strs(input_strs);
     let mut output = Grid::new_fn(input.size, |_| None);
-    let mut ctx: Context<u8> = Context::default();
+    let mut ctx: Context<u8> = Context::new(mode);
     ctx.for_each_visible(
         eye,
         &TestInputGrid,
@@ -158,6 +162,28 @@ fn check_scenario_with_vision_distance<VD: VisionDistance>(
     check_output(eye, &output, expected_output);
 }
 
+fn check_scenario_with_vision_distance<VD: VisionDistance>(
+    vision_distance: VD,
+    input_strs: &[&str],
+    expected_output: &[&str],
+) {
+    check_scenario_with_mode_and_vision_distance(
+        Mode::Asymmetric,
+        vision_distance,
+        input_strs,
+        expected_output,
+    );
+}
+
+fn check_scenario_with_mode(mode: Mode, input_strs: &[&str], expected_output: &[&str]) {
+    check_scenario_with_mode_and_vision_distance(
+        mode,
+        vision_distance::Circle::new(100),
+        input_strs,
+        expected_output,
+    );
+}
+
 fn check_scenario(input_strs: &[&str], expected_output: &[&str]) {
     check_scenario_with_vision_distance(
         vision_distance::Circle::new(100),
@@ -752,3 +778,2656 @@ fn diamond() {
         ],
     );
 }
+
+#[test]
+fn ellipse() {
+    check_scenario_with_vision_distance(
+        vision_distance::Ellipse::new(10.5, 3.5),
+        &[
+            ".........................",
+            ".........................",
+            ".........................",
+            ".........................",
+            "............@............",
+            ".........................",
+            ".........................",
+            ".........................",
+            ".........................",
+        ],
+        &[
+            "%%%%%%%%%%%%%%%%%%%%%%%%%",
+            "%%%%%%%,,,,,,,,,,,%%%%%%%",
+            "%%%%,,,,,,,,,,,,,,,,,%%%%",
+            "%%,,,,,,,,,,,,,,,,,,,,,%%",
+            "%%,,,,,,,,,,@,,,,,,,,,,%%",
+            "%%,,,,,,,,,,,,,,,,,,,,,%%",
+            "%%%%,,,,,,,,,,,,,,,,,%%%%",
+            "%%%%%%%,,,,,,,,,,,%%%%%%%",
+            "%%%%%%%%%%%%%%%%%%%%%%%%%",
+        ],
+    );
+}
+
+#[test]
+fn ellipse_circle() {
+    for radius in 0..20 {
+        let circle = vision_distance::Circle::new(radius);
+        let ellipse = vision_distance::Ellipse::circle(radius as f64);
+        // (r + 0.5)^2 = r^2 + r + 0.25
+        let fractional = vision_distance::Ellipse::circle(radius as f64 + 0.5);
+        let fractional_circle =
+            vision_distance::Circle::new_squared(radius * radius + radius);
+        assert_eq!(ellipse.max_depth(), circle.max_depth());
+        assert_eq!(fractional.max_depth(), circle.max_depth());
+        for delta in Size::new(41, 41).coord_iter_row_major() {
+            let delta = delta - Coord::new(20, 20);
+            assert_eq!(ellipse.in_range(delta), circle.in_range(delta));
+            assert_eq!(
+                fractional.in_range(delta),
+                fractional_circle.in_range(delta)
+            );
+        }
+    }
+}
+
+#[test]
+fn facing() {
+    check_scenario_with_vision_distance(
+        vision_distance::Facing::from_direction(Direction::East, 12.0, 5.0, 2.0),
+        &[
+            "...................",
+            "...................",
+            "...................",
+            "...................",
+            "...................",
+            "...................",
+            "...@...............",
+            "...................",
+            "...................",
+            "...................",
+            "...................",
+            "...................",
+            "...................",
+        ],
+        &[
+            "%%%%,,,,,,,,,%%%%%%",
+            "%%%,,,,,,,,,,,%%%%%",
+            "%%,,,,,,,,,,,,%%%%%",
+            "%%,,,,,,,,,,,,,%%%%",
+            "%,,,,,,,,,,,,,,%%%%",
+            "%,,,,,,,,,,,,,,%%%%",
+            "%,,@,,,,,,,,,,,,%%%",
+            "%,,,,,,,,,,,,,,%%%%",
+            "%,,,,,,,,,,,,,,%%%%",
+            "%%,,,,,,,,,,,,,%%%%",
+            "%%,,,,,,,,,,,,%%%%%",
+            "%%%,,,,,,,,,,,%%%%%",
+            "%%%%,,,,,,,,,%%%%%%",
+        ],
+    );
+    let facing =
+        vision_distance::Facing::from_direction(Direction::North, 12.0, 5.0, 2.0);
+    assert_eq!(facing.max_depth(), Some(12));
+    for (delta, in_range) in [
+        (Coord::new(0, -12), true),
+        (Coord::new(0, -13), false),
+        (Coord::new(5, 0), true),
+        (Coord::new(-6, 0), false),
+        (Coord::new(0, 2), true),
+        (Coord::new(0, 3), false),
+    ] {
+        assert_eq!(facing.in_range(delta), in_range, "{:?}", delta);
+    }
+}
+
+#[test]
+fn mask() {
+    let mask = vision_distance::Mask::from_strs(&[
+        ".........",
+        "....#....",
+        "....#....",
+        "...###...",
+        "#########",
+        "...###...",
+        "....#....",
+        "....#....",
+        ".........",
+    ]);
+    assert_eq!(mask.radius(), 4);
+    assert_eq!((&mask).max_depth(), Some(4));
+    check_scenario_with_vision_distance(
+        &mask,
+        &[
+            "...........",
+            "...........",
+            "...........",
+            "...........",
+            ".....@.....",
+            "...........",
+            "...........",
+            "...........",
+            "...........",
+        ],
+        &[
+            "%%%%%%%%%%%",
+            "%%%%%,%%%%%",
+            "%%%%%,%%%%%",
+            "%%%%,,,%%%%",
+            "%,,,,@,,,,%",
+            "%%%%,,,%%%%",
+            "%%%%%,%%%%%",
+            "%%%%%,%%%%%",
+            "%%%%%%%%%%%",
+        ],
+    );
+    let keyhole = vision_distance::Mask::from_fn(6, |delta| {
+        delta.x.abs() + delta.y.abs() <= 1 || (delta.x == 0 && (0..3).contains(&delta.y))
+    });
+    assert_eq!((&keyhole).max_depth(), Some(2));
+    assert!((&keyhole).in_range(Coord::new(0, 2)));
+    assert!(!(&keyhole).in_range(Coord::new(0, -2)));
+    assert!(!(&keyhole).in_range(Coord::new(0, 7)));
+}
+
+struct CountingInputGrid {
+    count: std::cell::Cell<usize>,
+}
+
+impl InputGrid for CountingInputGrid {
+    type Grid = Grid<u8>;
+    type Opacity = u8;
+    fn size(&self, grid: &Self::Grid) -> Size {
+        grid.size
+    }
+    fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity {
+        self.count.set(self.count.get() + 1);
+        *grid.get(coord).unwrap()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct UnboundedCircle(vision_distance::Circle);
+
+impl VisionDistance for UnboundedCircle {
+    fn in_range(self, delta: Coord) -> bool {
+        self.0.in_range(delta)
+    }
+}
+
+fn count_visible_and_lookups<VD: VisionDistance>(vision_distance: VD) -> (usize, usize) {
+    let input = Grid::new_fn(Size::new(201, 201), |_| 0);
+    let input_grid = CountingInputGrid {
+        count: std::cell::Cell::new(0),
+    };
+    let mut ctx: Context<u8> = Context::default();
+    let mut visible = 0;
+    ctx.for_each_visible(
+        Coord::new(100, 100),
+        &input_grid,
+        &input,
+        vision_distance,
+        255,
+        |_, _, _| visible += 1,
+    );
+    (visible, input_grid.count.get())
+}
+
+#[test]
+fn scan_stops_at_vision_distance() {
+    let side = 11;
+    for (visible, lookups) in [
+        count_visible_and_lookups(vision_distance::Circle::new(5)),
+        count_visible_and_lookups(vision_distance::Square::new(5)),
+        count_visible_and_lookups(vision_distance::Diamond::new(5)),
+    ] {
+        assert!(visible <= side * side);
+        // cells along the axes and diagonals are scanned by two octants
+        assert!(lookups <= 2 * side * side, "{} lookups", lookups);
+    }
+    assert_eq!(
+        vision_distance::Circle::new_squared(90).max_depth(),
+        Some(9)
+    );
+}
+
+#[test]
+fn custom_vision_distance_without_max_depth() {
+    let bounded = count_visible_and_lookups(vision_distance::Circle::new(5));
+    let unbounded =
+        count_visible_and_lookups(UnboundedCircle(vision_distance::Circle::new(5)));
+    assert_eq!(bounded.0, unbounded.0);
+    assert!(unbounded.1 > 201 * 201);
+}
+
+fn check_cone_scenario(cone: Cone, input_strs: &[&str], expected_output: &[&str]) {
+    let (input, eye) = input_from_strs(input_strs);
+    let mut output = Grid::new_fn(input.size, |_| None);
+    let mut ctx: Context<u8> = Context::default();
+    ctx.for_each_visible(
+        View::new(eye).cone(cone),
+        &TestInputGrid,
+        &input,
+        vision_distance::Circle::new(100),
+        255,
+        |coord, direction_map, _visibility| {
+            let v = output.get_mut(coord).unwrap();
+            if v.is_some() {
+                panic!("already have value at {:?}", coord);
+            }
+            *v = Some(direction_map);
+        },
+    );
+    check_output(eye, &output, expected_output);
+}
+
+#[test]
+fn cone_quadrant() {
+    check_cone_scenario(
+        Cone::from_direction(Direction::North, std::f64::consts::FRAC_PI_4),
+        &[
+            "...........",
+            "...........",
+            "...........",
+            "...........",
+            ".....@.....",
+            "...........",
+            "...........",
+            "...........",
+            "...........",
+        ],
+        &[
+            "%,,,,,,,,,%",
+            "%%,,,,,,,%%",
+            "%%%,,,,,%%%",
+            "%%%%,,,%%%%",
+            "%%%%%@%%%%%",
+            "%%%%%%%%%%%",
+            "%%%%%%%%%%%",
+            "%%%%%%%%%%%",
+            "%%%%%%%%%%%",
+        ],
+    );
+}
+
+#[test]
+fn cone_narrow() {
+    check_cone_scenario(
+        Cone::new(0.3, 0.2),
+        &[
+            "...............",
+            "...............",
+            "...............",
+            "...............",
+            "..@............",
+            "...............",
+            "......#........",
+            "...............",
+            "...............",
+        ],
+        &[
+            "%%%%%%%%%%%%%%%",
+            "%%%%%%%%%%%%%%%",
+            "%%%%%%%%%%%%%%%",
+            "%%%%%%%%%%%%%%%",
+            "%%@,,,,%%%%%%%%",
+            "%%%,,,,,,,,,,,,",
+            "%%%%%,▛,,,,,,,,",
+            "%%%%%%%%%%,,,,,",
+            "%%%%%%%%%%%%%,,",
+        ],
+    );
+}
+
+#[test]
+fn cone_reports_cells_in_cone_once() {
+    use std::f64::consts::PI;
+    let size = Size::new(21, 21);
+    let input = Grid::new_fn(size, |_| 0);
+    let eye = Coord::new(10, 10);
+    let mut ctx: Context<u8> = Context::default();
+    for i in 0..32 {
+        for &half_angle in &[0.1, 0.5, PI / 4.0, 2.0, 3.0, PI - 0.17] {
+            let cone = Cone::new(i as f64 * PI / 16.0 + 0.01, half_angle);
+            let mut output = Grid::new_fn(size, |_| false);
+            ctx.for_each_visible(
+                View::new(eye).cone(cone),
+                &TestInputGrid,
+                &input,
+                vision_distance::Square::new(10),
+                255,
+                |coord, _, _| {
+                    let v = output.get_mut(coord).unwrap();
+                    assert!(!*v, "already have value at {:?}", coord);
+                    *v = true;
+                },
+            );
+            for y in 0..21 {
+                for x in 0..21 {
+                    let delta = Coord::new(x, y) - eye;
+                    if delta == Coord::new(0, 0) {
+                        continue;
+                    }
+                    let angle = (delta.y as f64).atan2(delta.x as f64);
+                    let offset = (angle - cone.facing() + PI).rem_euclid(2.0 * PI) - PI;
+                    // the range of angles covered by the cell, relative to the facing
+                    let (min_offset, max_offset) = [(-1, -1), (1, -1), (-1, 1), (1, 1)]
+                        .iter()
+                        .map(|&(corner_x, corner_y)| {
+                            let corner_x = delta.x as f64 + corner_x as f64 * 0.5;
+                            let corner_y = delta.y as f64 + corner_y as f64 * 0.5;
+                            let corner_angle = corner_y.atan2(corner_x);
+                            offset + (corner_angle - angle + PI).rem_euclid(2.0 * PI) - PI
+                        })
+                        .fold((f64::MAX, f64::MIN), |(min, max), corner_offset| {
+                            (min.min(corner_offset), max.max(corner_offset))
+                        });
+                    let intersects = [-2.0 * PI, 0.0, 2.0 * PI].iter().any(|turn| {
+                        min_offset + turn < half_angle + 0.01
+                            && max_offset + turn > -half_angle - 0.01
+                    });
+                    // the centres of cells well within the cone must be visible, and cells
+                    // which don't intersect the cone must not be
+                    let visible = *output.get(Coord::new(x, y)).unwrap();
+                    if offset.abs() < half_angle - 0.01 {
+                        assert!(visible, "{:?} {:?}", cone, delta);
+                    } else if !intersects {
+                        assert!(!visible, "{:?} {:?}", cone, delta);
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        // xorshift64*
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as u32
+    }
+    fn below(&mut self, n: u32) -> u32 {
+        self.next() % n
+    }
+}
+
+fn random_grid(rng: &mut Rng, size: Size, partial: bool) -> Grid<u8> {
+    Grid::new_fn(size, |_| match rng.below(20) {
+        0..=4 => 255,
+        5..=7 if partial => 100,
+        _ => 0,
+    })
+}
+
+fn visibility_grid<VD: VisionDistance>(
+    ctx: &mut Context<u8>,
+    input: &Grid<u8>,
+    eye: Coord,
+    vision_distance: VD,
+) -> Grid<Option<u8>> {
+    let mut output = Grid::new_fn(input.size, |_| None);
+    ctx.for_each_visible(
+        eye,
+        &TestInputGrid,
+        input,
+        vision_distance,
+        255,
+        |coord, _, visibility| {
+            let cell = output.get_mut(coord).unwrap();
+            *cell = cmp::max(*cell, Some(visibility));
+        },
+    );
+    output
+}
+
+#[test]
+fn cone_is_subset_of_full_view() {
+    use std::f64::consts::PI;
+    let mut rng = Rng(0xc0_4e5e);
+    let size = Size::new(24, 17);
+    let vision_distance = vision_distance::Circle::new(12);
+    for i in 0..60 {
+        let mut ctx: Context<u8> = Context::new(match i % 3 {
+            0 => Mode::Asymmetric,
+            1 => Mode::Symmetric,
+            _ => Mode::Permissive(8),
+        });
+        let input = random_grid(&mut rng, size, true);
+        let eye = Coord::new(rng.below(24) as i32, rng.below(17) as i32);
+        let expected = visibility_grid(&mut ctx, &input, eye, vision_distance);
+        for _ in 0..8 {
+            let facing = rng.below(6283) as f64 / 1000.0 - PI;
+            let half_angle = rng.below(3141) as f64 / 1000.0 + 0.001;
+            let cone = Cone::new(facing, half_angle);
+            let mut output = Grid::new_fn(size, |_| None);
+            ctx.for_each_visible(
+                View::new(eye).cone(cone),
+                &TestInputGrid,
+                &input,
+                vision_distance,
+                255,
+                |coord, _, visibility| {
+                    let cell = output.get_mut(coord).unwrap();
+                    *cell = cmp::max(*cell, Some(visibility));
+                },
+            );
+            for coord in size.coord_iter_row_major() {
+                let visibility = *output.get(coord).unwrap();
+                let expected = *expected.get(coord).unwrap();
+                // lines of sight through the edge of a cell may lie outside the cone
+                if visibility.is_some() {
+                    assert!(expected >= visibility, "{:?} {:?} {:?}", cone, eye, coord);
+                }
+                // cells which are within the cone from anywhere in the eye's cell are
+                // seen as they are without it
+                let delta = coord - eye;
+                let angle = (delta.y as f64).atan2(delta.x as f64);
+                let offset = (angle - cone.facing() + PI).rem_euclid(2.0 * PI) - PI;
+                let within = (delta.x.abs() > 1 || delta.y.abs() > 1)
+                    && [(-1, -1), (1, -1), (-1, 1), (1, 1)].iter().all(
+                        |&(corner_x, corner_y)| {
+                            let x = (delta.x + corner_x) as f64;
+                            let y = (delta.y + corner_y) as f64;
+                            let corner_offset = offset
+                                + (y.atan2(x) - angle + PI).rem_euclid(2.0 * PI)
+                                - PI;
+                            corner_offset.abs() < half_angle - 0.01
+                        },
+                    );
+                if within {
+                    assert_eq!(visibility, expected, "{:?} {:?} {:?}", cone, eye, coord);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn is_visible_matches_for_each_visible() {
+    let mut rng = Rng(0x1234_5678);
+    let size = Size::new(24, 17);
+    let vision_distance = vision_distance::Circle::new(10);
+    for i in 0..45 {
+        let mut ctx: Context<u8> = Context::new(match i % 3 {
+            0 => Mode::Asymmetric,
+            1 => Mode::Symmetric,
+            _ => Mode::Permissive(8),
+        });
+        let input = random_grid(&mut rng, size, true);
+        for _ in 0..5 {
+            let eye = Coord::new(rng.below(24) as i32, rng.below(17) as i32);
+            let expected = visibility_grid(&mut ctx, &input, eye, vision_distance);
+            for to in size.coord_iter_row_major() {
+                let visibility =
+                    ctx.is_visible(eye, to, &TestInputGrid, &input, vision_distance, 255);
+                assert_eq!(
+                    visibility,
+                    *expected.get(to).unwrap(),
+                    "from {:?} to {:?}",
+                    eye,
+                    to
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn symmetric_mode_is_symmetric() {
+    let mut rng = Rng(0x9e37_79b9);
+    let size = Size::new(20, 15);
+    let vision_distance = vision_distance::Circle::new(30);
+    let mut ctx: Context<u8> = Context::new(Mode::Symmetric);
+    for _ in 0..30 {
+        let input = random_grid(&mut rng, size, false);
+        let transparent = size
+            .coord_iter_row_major()
+            .filter(|&coord| *input.get(coord).unwrap() == 0)
+            .collect::<Vec<_>>();
+        let visible = Grid::new_fn(size, |coord| {
+            if *input.get(coord).unwrap() == 0 {
+                Some(visibility_grid(&mut ctx, &input, coord, vision_distance))
+            } else {
+                None
+            }
+        });
+        for &a in &transparent {
+            let from_a = visible.get(a).unwrap().as_ref().unwrap();
+            for &b in &transparent {
+                let from_b = visible.get(b).unwrap().as_ref().unwrap();
+                assert_eq!(
+                    from_a.get(b).unwrap().is_some(),
+                    from_b.get(a).unwrap().is_some(),
+                    "{:?} and {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn symmetric_pillar() {
+    check_scenario_with_mode(
+        Mode::Symmetric,
+        &[
+            "...........",
+            "...........",
+            "...........",
+            "...........",
+            "......#....",
+            "...........",
+            "...@.......",
+            "...........",
+        ],
+        &[
+            ",,,,,,,,,,,",
+            ",,,,,,,,,,%",
+            ",,,,,,,,%%%",
+            ",,,,,,,%%,,",
+            ",,,,,,▙,,,,",
+            ",,,,,,,,,,,",
+            ",,,@,,,,,,,",
+            ",,,,,,,,,,,",
+        ],
+    );
+}
+
+#[test]
+fn symmetric_mode_reports_each_cell_once() {
+    // partially opaque cells and thin walls split sections where the visibility changes,
+    // and the cell on the boundary must only be reported by one of them
+    let mut rng = Rng(0x5e_c710);
+    let size = Size::new(25, 19);
+    let mut ctx: Context<u8> = Context::new(Mode::Symmetric);
+    for i in 0..200 {
+        let cells = random_grid(&mut rng, size, true);
+        let mut walls = HashMap::new();
+        if i % 2 == 1 {
+            for coord in size.coord_iter_row_major() {
+                for edge in [CellEdge::North, CellEdge::West] {
+                    match rng.below(10) {
+                        0 => walls.insert((coord, edge), 255),
+                        1 | 2 => walls.insert((coord, edge), 100),
+                        _ => None,
+                    };
+                }
+            }
+        }
+        let walled = Walled { cells, walls };
+        let eye = Coord::new(rng.below(25) as i32, rng.below(19) as i32);
+        let mut seen = Grid::new_fn(size, |_| false);
+        ctx.for_each_visible(
+            eye,
+            &WallInputGrid,
+            &walled,
+            vision_distance::Circle::new(12),
+            255,
+            |coord, _, _| {
+                let cell = seen.get_mut(coord).unwrap();
+                assert!(!*cell, "{:?} reported twice from {:?}", coord, eye);
+                *cell = true;
+            },
+        );
+    }
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn context_serde_round_trip() {
+    // the serialized form of a context is the same as before there were modes
+    let ctx: Context<u8> = Context::new(Mode::Symmetric);
+    let serialized = serde_json::to_string(&ctx).unwrap();
+    assert_eq!(serialized, "null");
+    let ctx: Context<u8> = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(ctx.mode(), Mode::Asymmetric);
+    let mode = Mode::Permissive(3);
+    let serialized = serde_json::to_string(&mode).unwrap();
+    assert_eq!(serde_json::from_str::<Mode>(&serialized).unwrap(), mode);
+}
+
+#[test]
+fn permissive_diagonal_corridor() {
+    check_scenario_with_mode(
+        Mode::Permissive(8),
+        &[
+            "#########",
+            "#######.#",
+            "######..#",
+            "#####..##",
+            "####..###",
+            "###..####",
+            "##@.#####",
+            "#########",
+        ],
+        &[
+            "%%%%%%%%▖",
+            "%%%%%%%,▌",
+            "%%%%%%,,%",
+            "%%%%▄,,▌%",
+            "%%%%,,▌%%",
+            "%▗▄,,%%%%",
+            "%▐@,▌%%%%",
+            "%▝▀▀▌%%%%",
+        ],
+    );
+}
+
+#[test]
+fn permissive_sees_more() {
+    let mut rng = Rng(0xdead_beef);
+    let size = Size::new(24, 17);
+    let vision_distance = vision_distance::Circle::new(12);
+    let mut asymmetric: Context<u8> = Context::new(Mode::Asymmetric);
+    let mut permissive: Context<u8> = Context::new(Mode::Permissive(4));
+    let mut more_permissive: Context<u8> = Context::new(Mode::Permissive(8));
+    let mut total_visible = [0; 3];
+    for _ in 0..20 {
+        let input = random_grid(&mut rng, size, true);
+        let eye = Coord::new(rng.below(24) as i32, rng.below(17) as i32);
+        let grids = [
+            visibility_grid(&mut asymmetric, &input, eye, vision_distance),
+            visibility_grid(&mut permissive, &input, eye, vision_distance),
+            visibility_grid(&mut more_permissive, &input, eye, vision_distance),
+        ];
+        for coord in size.coord_iter_row_major() {
+            // the centre of the eye's cell is always one of the points cells are seen from
+            let asymmetric = grids[0].get(coord).unwrap();
+            for grid in &grids[1..] {
+                assert!(
+                    grid.get(coord).unwrap() >= asymmetric,
+                    "{:?} from {:?}",
+                    coord,
+                    eye
+                );
+            }
+            for (total, grid) in total_visible.iter_mut().zip(grids.iter()) {
+                *total += grid.get(coord).unwrap().is_some() as usize;
+            }
+        }
+    }
+    assert!(total_visible[0] < total_visible[1]);
+    assert!(total_visible[1] < total_visible[2]);
+}
+
+#[test]
+fn permissive_levels() {
+    let mut rng = Rng(0x5eed_1e7e);
+    let size = Size::new(24, 17);
+    let vision_distance = vision_distance::Circle::new(12);
+    let mut ctx: Context<u8> = Context::default();
+    // whether each level has been seen to differ from the level below it
+    let mut differs = [false; 7];
+    for _ in 0..20 {
+        let input = random_grid(&mut rng, size, false);
+        let eye = Coord::new(rng.below(24) as i32, rng.below(17) as i32);
+        let grids = (0..=9)
+            .chain([255])
+            .map(|level| {
+                ctx.set_mode(Mode::Permissive(level));
+                visibility_grid(&mut ctx, &input, eye, vision_distance)
+            })
+            .collect::<Vec<_>>();
+        let same = |a: &Grid<Option<u8>>, b: &Grid<Option<u8>>| {
+            size.coord_iter_row_major()
+                .all(|coord| a.get(coord) == b.get(coord))
+        };
+        for (level, differs) in differs.iter_mut().enumerate() {
+            *differs |= !same(&grids[level], &grids[level + 1]);
+        }
+        // levels above 7 are clamped
+        for grid in &grids[8..] {
+            assert!(same(grid, &grids[7]));
+        }
+    }
+    assert_eq!(differs, [true; 7]);
+}
+
+fn visibility_grid_with_eye_offset(
+    ctx: &mut Context<u8>,
+    input: &Grid<u8>,
+    eye: Coord,
+    eye_offset: Coord,
+) -> Grid<Option<u8>> {
+    let mut output = Grid::new_fn(input.size, |_| None);
+    ctx.for_each_visible(
+        View::new(eye).eye_offset(eye_offset),
+        &TestInputGrid,
+        input,
+        vision_distance::Circle::new(12),
+        255,
+        |coord, _, visibility| {
+            let cell = output.get_mut(coord).unwrap();
+            assert!(cell.is_none(), "already have value at {:?}", coord);
+            *cell = Some(visibility);
+        },
+    );
+    output
+}
+
+#[test]
+fn eye_offset_is_reflected() {
+    let mut rng = Rng(0x0bad_cafe);
+    let size = Size::new(20, 20);
+    let max = size.x() as i32 - 1;
+    let reflections: [fn(Coord, i32) -> Coord; 3] = [
+        |coord, max| Coord::new(max - coord.x, coord.y),
+        |coord, max| Coord::new(coord.x, max - coord.y),
+        |coord, _| Coord::new(coord.y, coord.x),
+    ];
+    let mut ctx: Context<u8> = Context::default();
+    for i in 0..60 {
+        let reflect = |coord| reflections[i % 3](coord, max);
+        let reflect_offset = |offset| reflections[i % 3](offset, 0);
+        let input = random_grid(&mut rng, size, true);
+        let reflected_input =
+            Grid::new_fn(size, |coord| *input.get(reflect(coord)).unwrap());
+        let eye = Coord::new(rng.below(20) as i32, rng.below(20) as i32);
+        let eye_offset = Coord::new(rng.below(15) as i32 - 7, rng.below(15) as i32 - 7);
+        let visible = visibility_grid_with_eye_offset(&mut ctx, &input, eye, eye_offset);
+        let reflected_visible = visibility_grid_with_eye_offset(
+            &mut ctx,
+            &reflected_input,
+            reflect(eye),
+            reflect_offset(eye_offset),
+        );
+        for coord in size.coord_iter_row_major() {
+            assert_eq!(
+                visible.get(coord).unwrap(),
+                reflected_visible.get(reflect(coord)).unwrap(),
+                "{:?} from {:?} offset by {:?}",
+                coord,
+                eye,
+                eye_offset,
+            );
+        }
+    }
+}
+
+#[test]
+fn eye_offset_zero() {
+    let mut rng = Rng(0x5eed);
+    let size = Size::new(24, 17);
+    let mut ctx: Context<u8> = Context::default();
+    for _ in 0..10 {
+        let input = random_grid(&mut rng, size, true);
+        let eye = Coord::new(rng.below(24) as i32, rng.below(17) as i32);
+        assert_eq!(
+            visibility_grid_with_eye_offset(&mut ctx, &input, eye, Coord::new(0, 0))
+                .cells,
+            visibility_grid(&mut ctx, &input, eye, vision_distance::Circle::new(12))
+                .cells,
+        );
+    }
+}
+
+#[test]
+fn footprint_is_union_of_cells() {
+    let mut rng = Rng(0xf00d);
+    let size = Size::new(24, 17);
+    let vision_distance = vision_distance::Circle::new(8);
+    let mut ctx: Context<u8> = Context::default();
+    for footprint in [
+        Size::new(1, 1),
+        Size::new(2, 2),
+        Size::new(3, 2),
+        Size::new(3, 3),
+        Size::new(4, 3),
+    ] {
+        for i in 0..10 {
+            let mut input = random_grid(&mut rng, size, true);
+            let top_left = Coord::new(rng.below(24) as i32, rng.below(17) as i32);
+            if i % 2 == 1 {
+                // cells in the middle of an opaque viewer can't see anything more
+                for offset in footprint.coord_iter_row_major() {
+                    if let Some(cell) = input.get_mut(top_left + offset) {
+                        *cell = 255;
+                    }
+                }
+            }
+            let mut expected = Grid::new_fn(size, |_| None);
+            for offset in footprint.coord_iter_row_major() {
+                ctx.for_each_visible(
+                    top_left + offset,
+                    &TestInputGrid,
+                    &input,
+                    vision_distance,
+                    255,
+                    |coord, bitmap, visibility| {
+                        let cell = expected.get_mut(coord).unwrap();
+                        *cell = Some(match *cell {
+                            None => (bitmap, visibility),
+                            Some((b, v)) => (b | bitmap, cmp::max(v, visibility)),
+                        });
+                    },
+                );
+            }
+            let mut output = Grid::new_fn(size, |_| None);
+            ctx.for_each_visible(
+                View::new(top_left).footprint(footprint),
+                &TestInputGrid,
+                &input,
+                vision_distance,
+                255,
+                |coord, bitmap, visibility| {
+                    let cell = output.get_mut(coord).unwrap();
+                    assert!(cell.is_none(), "already have value at {:?}", coord);
+                    *cell = Some((bitmap, visibility));
+                },
+            );
+            for coord in size.coord_iter_row_major() {
+                let expected =
+                    expected.get(coord).unwrap().map(|(bitmap, visibility)| {
+                        // edges seen from one cell supersede corners seen from another
+                        let cardinal = bitmap & DirectionBitmap::all_cardinal();
+                        if bitmap.is_full() || cardinal.is_empty() {
+                            (bitmap, visibility)
+                        } else {
+                            (cardinal, visibility)
+                        }
+                    });
+                assert_eq!(*output.get(coord).unwrap(), expected, "{:?}", coord);
+            }
+        }
+    }
+}
+
+struct HeightInputGrid;
+
+impl InputGrid for HeightInputGrid {
+    type Grid = Grid<(u8, Option<i32>)>;
+    type Opacity = u8;
+    fn size(&self, grid: &Self::Grid) -> Size {
+        grid.size
+    }
+    fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity {
+        grid.get(coord).unwrap().0
+    }
+    fn get_height(&self, grid: &Self::Grid, coord: Coord) -> Option<i32> {
+        grid.get(coord).unwrap().1
+    }
+}
+
+/// Digits are opaque cells of that height, and other cells are as in `input_from_strs`,
+/// with transparent cells at height 0
+fn height_input_from_strs(strs: &[&str]) -> (Grid<(u8, Option<i32>)>, Coord) {
+    let digits = strs
+        .iter()
+        .map(|row| row.replace(|ch: char| ch.is_ascii_digit(), "#"))
+        .collect::<Vec<_>>();
+    let (input, eye) =
+        input_from_strs(&digits.iter().map(String::as_str).collect::<Vec<_>>());
+    let grid = Grid::new_fn(input.size, |coord| {
+        let ch = strs[coord.y as usize].as_bytes()[coord.x as usize] as char;
+        let opacity = *input.get(coord).unwrap();
+        let height = match ch {
+            '#' => None,
+            '0'..='9' => Some(ch as i32 - '0' as i32),
+            _ => Some(0),
+        };
+        (opacity, height)
+    });
+    (grid, eye)
+}
+
+fn check_height_scenario(eye_height: i32, input_strs: &[&str], expected_output: &[&str]) {
+    let (input, eye) = height_input_from_strs(input_strs);
+    let mut output = Grid::new_fn(input.size, |_| None);
+    let mut ctx: Context<u8> = Context::default();
+    ctx.for_each_visible(
+        View::new(eye).eye_height(eye_height),
+        &HeightInputGrid,
+        &input,
+        vision_distance::Circle::new(100),
+        255,
+        |coord, direction_map, _visibility| {
+            let v = output.get_mut(coord).unwrap();
+            assert!(v.is_none(), "already have value at {:?}", coord);
+            *v = Some(direction_map);
+        },
+    );
+    check_output(eye, &output, expected_output);
+}
+
+#[test]
+fn low_wall() {
+    check_height_scenario(
+        2,
+        &[
+            "...............",
+            "...............",
+            "...............",
+            "...............",
+            "...............",
+            "........5......",
+            "...............",
+            "....1111111....",
+            "...............",
+            "...............",
+            ".......@.......",
+        ],
+        &[
+            ",,,,,,,,,%%,,,,",
+            ",,,,,,,,,%%,,,,",
+            ",,,,,,,,,%%,,,,",
+            ",,,,,,,,,%,,,,,",
+            ",%%%%%%%%%%%%%,",
+            ",%%%%%%%▙%%%%%,",
+            ",,,%%%%%%%%%,,,",
+            ",,,,▄▄▄▄▄▄▄,,,,",
+            ",,,,,,,,,,,,,,,",
+            ",,,,,,,,,,,,,,,",
+            ",,,,,,,@,,,,,,,",
+        ],
+    );
+}
+
+#[test]
+fn ledge() {
+    check_height_scenario(
+        9,
+        &[
+            "...............",
+            "...............",
+            "...............",
+            "....3333333....",
+            "...............",
+            "...............",
+            "555555555555555",
+            "8888888@8888888",
+        ],
+        &[
+            ",,,,,,,,,,,,,,,",
+            "%%%%%%%%%%%%%%%",
+            "%%%%%%%%%%%%%%%",
+            "%%%,▄▄▄▄▄▄▄,%%%",
+            "%%%%,,,,,,,%%%%",
+            "%%%%%%%%%%%%%%%",
+            "%%%%%%▄▄▄%%%%%%",
+            "▐▐▐▐▐▐▐@▌▌▌▌▌▌▌",
+        ],
+    );
+}
+
+#[test]
+fn heights_report_each_cell_once() {
+    // sections split where only the horizon changes, and the cell on the boundary must
+    // only be reported by one of them
+    let mut rng = Rng(0x4e16_4700);
+    let size = Size::new(21, 17);
+    let mut ctx: Context<u8> = Context::default();
+    for _ in 0..400 {
+        let input = Grid::new_fn(size, |_| match rng.below(10) {
+            0 => (255, None),
+            1..=3 => (255, Some(rng.below(6) as i32)),
+            _ => (0, Some(0)),
+        });
+        let eye = Coord::new(rng.below(21) as i32, rng.below(17) as i32);
+        let eye_height = rng.below(6) as i32;
+        let mut seen = Grid::new_fn(size, |_| false);
+        ctx.for_each_visible(
+            View::new(eye).eye_height(eye_height),
+            &HeightInputGrid,
+            &input,
+            vision_distance::Circle::new(12),
+            255,
+            |coord, _, _| {
+                let cell = seen.get_mut(coord).unwrap();
+                assert!(!*cell, "{:?} reported twice from {:?}", coord, eye);
+                *cell = true;
+            },
+        );
+    }
+}
+
+#[test]
+fn without_heights() {
+    let mut rng = Rng(0xabcd);
+    let size = Size::new(24, 17);
+    let vision_distance = vision_distance::Circle::new(12);
+    let mut ctx: Context<u8> = Context::default();
+    for _ in 0..10 {
+        let input = random_grid(&mut rng, size, true);
+        let eye = Coord::new(rng.below(24) as i32, rng.below(17) as i32);
+        let mut output = Grid::new_fn(size, |_| None);
+        ctx.for_each_visible(
+            View::new(eye).eye_height(2),
+            &TestInputGrid,
+            &input,
+            vision_distance,
+            255,
+            |coord, _, visibility| *output.get_mut(coord).unwrap() = Some(visibility),
+        );
+        assert_eq!(
+            output.cells,
+            visibility_grid(&mut ctx, &input, eye, vision_distance).cells
+        );
+    }
+}
+
+struct WrapInputGrid(Wrap);
+
+impl InputGrid for WrapInputGrid {
+    type Grid = Grid<u8>;
+    type Opacity = u8;
+    fn size(&self, grid: &Self::Grid) -> Size {
+        grid.size
+    }
+    fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity {
+        *grid.get(coord).unwrap()
+    }
+    fn wrap(&self, _grid: &Self::Grid) -> Wrap {
+        self.0
+    }
+}
+
+fn check_wrap_scenario(wrap: Wrap, input_strs: &[&str], expected_output: &[&str]) {
+    let (input, eye) = input_from_strs(input_strs);
+    let mut output = Grid::new_fn(input.size, |_| None);
+    let mut ctx: Context<u8> = Context::default();
+    ctx.for_each_visible(
+        eye,
+        &WrapInputGrid(wrap),
+        &input,
+        vision_distance::Circle::new(6),
+        255,
+        |coord, direction_map, _visibility| {
+            let v = output.get_mut(coord).unwrap();
+            assert!(v.is_none(), "already have value at {:?}", coord);
+            *v = Some(direction_map);
+        },
+    );
+    check_output(eye, &output, expected_output);
+}
+
+#[test]
+fn wrap_horizontal() {
+    check_wrap_scenario(
+        Wrap::Horizontal,
+        &[
+            "###########",
+            "...........",
+            ".#.......@.",
+            "...........",
+            "###########",
+        ],
+        &[
+            "▄▄▖%▄▄▄▄▄▄▄",
+            ",,%%,,,,,,,",
+            ",▌%,,,,,,@,",
+            ",,%%,,,,,,,",
+            "▀▀▘%▀▀▀▀▀▀▀",
+        ],
+    );
+}
+
+#[test]
+fn wrap_both_small() {
+    check_wrap_scenario(Wrap::Both, &["...", ".@.", "..."], &[",,,", ",@,", ",,,"]);
+}
+
+#[test]
+fn wrap_matches_tiled_grid() {
+    let mut rng = Rng(0x7a11);
+    let size = Size::new(13, 9);
+    let vision_distance = vision_distance::Circle::new(8);
+    let mut ctx: Context<u8> = Context::default();
+    for wrap in [Wrap::Horizontal, Wrap::Vertical, Wrap::Both] {
+        for _ in 0..10 {
+            let input = random_grid(&mut rng, size, true);
+            let eye = Coord::new(rng.below(13) as i32, rng.below(9) as i32);
+            let (tiles_x, tiles_y): (u32, u32) = match wrap {
+                Wrap::Horizontal => (3, 1),
+                Wrap::Vertical => (1, 3),
+                _ => (3, 3),
+            };
+            let tile = Coord::new(tiles_x as i32 / 2 * 13, tiles_y as i32 / 2 * 9);
+            let tiled = Grid::new_fn(Size::new(13 * tiles_x, 9 * tiles_y), |coord| {
+                *input
+                    .get(Coord::new(coord.x.rem_euclid(13), coord.y.rem_euclid(9)))
+                    .unwrap()
+            });
+            let mut expected = Grid::new_fn(size, |_| None);
+            ctx.for_each_visible(
+                eye + tile,
+                &TestInputGrid,
+                &tiled,
+                vision_distance,
+                255,
+                |coord, _, visibility| {
+                    let coord = Coord::new(coord.x.rem_euclid(13), coord.y.rem_euclid(9));
+                    let cell = expected.get_mut(coord).unwrap();
+                    *cell = cmp::max(*cell, Some(visibility));
+                },
+            );
+            let mut output = Grid::new_fn(size, |_| None);
+            ctx.for_each_visible(
+                eye,
+                &WrapInputGrid(wrap),
+                &input,
+                vision_distance,
+                255,
+                |coord, _, visibility| {
+                    let cell = output.get_mut(coord).unwrap();
+                    assert!(cell.is_none(), "already have value at {:?}", coord);
+                    *cell = Some(visibility);
+                },
+            );
+            assert_eq!(output.cells, expected.cells, "{:?} from {:?}", wrap, eye);
+            for to in size.coord_iter_row_major() {
+                let visibility = ctx.is_visible(
+                    eye,
+                    to,
+                    &WrapInputGrid(wrap),
+                    &input,
+                    vision_distance,
+                    255,
+                );
+                assert_eq!(
+                    visibility,
+                    *expected.get(to).unwrap(),
+                    "{:?} to {:?}",
+                    wrap,
+                    to
+                );
+            }
+        }
+    }
+}
+
+struct ChunkedInputGrid;
+
+impl InputGrid for ChunkedInputGrid {
+    type Grid = HashMap<Coord, u8>;
+    type Opacity = u8;
+    fn size(&self, _grid: &Self::Grid) -> Size {
+        unreachable!()
+    }
+    fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity {
+        grid.get(&coord).cloned().unwrap_or(0)
+    }
+    fn is_unbounded(&self, _grid: &Self::Grid) -> bool {
+        true
+    }
+}
+
+#[test]
+fn unbounded_matches_bounded() {
+    let mut rng = Rng(0xc4a2);
+    let size = Size::new(21, 21);
+    let eye = Coord::new(10, 10);
+    let offset = Coord::new(-30, -40);
+    let vision_distance = vision_distance::Circle::new(8);
+    let mut ctx: Context<u8> = Context::default();
+    for _ in 0..20 {
+        let input = random_grid(&mut rng, size, true);
+        let chunks = size
+            .coord_iter_row_major()
+            .map(|coord| (coord + offset, *input.get(coord).unwrap()))
+            .collect::<HashMap<_, _>>();
+        let mut expected = Vec::new();
+        ctx.for_each_visible(
+            eye,
+            &TestInputGrid,
+            &input,
+            vision_distance,
+            255,
+            |coord, bitmap, visibility| {
+                expected.push((coord + offset, bitmap, visibility))
+            },
+        );
+        let mut output = Vec::new();
+        ctx.for_each_visible(
+            eye + offset,
+            &ChunkedInputGrid,
+            &chunks,
+            vision_distance,
+            255,
+            |coord, bitmap, visibility| output.push((coord, bitmap, visibility)),
+        );
+        expected
+            .sort_by_key(|&(coord, bitmap, visibility)| (coord, bitmap.raw, visibility));
+        output
+            .sort_by_key(|&(coord, bitmap, visibility)| (coord, bitmap.raw, visibility));
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn unbounded_far_away() {
+    let eye = Coord::new(-1_000_000, -1_000_000);
+    let to = eye + Coord::new(40_000, 10_000);
+    let vision_distance = vision_distance::Square::new(50_000);
+    let mut chunks = HashMap::new();
+    let mut ctx: Context<u8> = Context::default();
+    assert_eq!(
+        ctx.is_visible(eye, to, &ChunkedInputGrid, &chunks, vision_distance, 255),
+        Some(255)
+    );
+    chunks.insert(eye + Coord::new(20_000, 5_000), 255);
+    assert_eq!(
+        ctx.is_visible(eye, to, &ChunkedInputGrid, &chunks, vision_distance, 255),
+        None
+    );
+    let vision_distance = vision_distance::Circle::new(50_000);
+    for (delta, visible) in [
+        (Coord::new(47_000, 0), true),
+        (Coord::new(-30_000, 40_000), true),
+        (Coord::new(40_000, 31_000), false),
+        (Coord::new(0, -50_001), false),
+    ] {
+        assert_eq!(
+            ctx.is_visible(
+                eye,
+                eye + delta,
+                &ChunkedInputGrid,
+                &chunks,
+                vision_distance,
+                255
+            ),
+            visible.then_some(255),
+            "{:?}",
+            delta
+        );
+    }
+    // distances are compared without overflowing at the extremes
+    for delta in [Coord::new(i32::MIN, 0), Coord::new(i32::MAX, i32::MAX)] {
+        assert!(!vision_distance.in_range(delta));
+        assert!(!vision_distance::Square::new(50_000).in_range(delta));
+    }
+    let diamond = vision_distance::Diamond::new(u32::MAX);
+    assert!(diamond.in_range(Coord::new(i32::MAX, i32::MAX)));
+    assert!(!diamond.in_range(Coord::new(i32::MIN, i32::MIN)));
+}
+
+#[test]
+fn unbounded_without_max_depth() {
+    // the scan stops where the vision distance does, even though it doesn't say where
+    let eye = Coord::new(-100, 50);
+    let chunks = HashMap::new();
+    let mut ctx: Context<u8> = Context::default();
+    let mut visible = Vec::new();
+    ctx.for_each_visible(
+        eye,
+        &ChunkedInputGrid,
+        &chunks,
+        UnboundedCircle(vision_distance::Circle::new(5)),
+        255,
+        |coord, _, _| visible.push(coord - eye),
+    );
+    let mut expected = Vec::new();
+    ctx.for_each_visible(
+        eye,
+        &ChunkedInputGrid,
+        &chunks,
+        vision_distance::Circle::new(5),
+        255,
+        |coord, _, _| expected.push(coord - eye),
+    );
+    visible.sort();
+    expected.sort();
+    assert_eq!(visible, expected);
+}
+
+fn fov_grid<L>(fov: &Fov<u8, L>, size: Size) -> Grid<Option<(DirectionBitmap, u8)>> {
+    let mut output = Grid::new_fn(size, |_| None);
+    for (coord, bitmap, visibility) in fov.iter() {
+        *output.get_mut(coord).unwrap() = Some((bitmap, visibility));
+    }
+    output
+}
+
+#[test]
+fn fov_update_cell_matches_observe_fov() {
+    let mut rng = Rng(0xd00c);
+    let size = Size::new(31, 25);
+    let vision_distance = vision_distance::Circle::new(12);
+    let mut ctx: Context<u8> = Context::default();
+    for mode in [Mode::Asymmetric, Mode::Symmetric] {
+        ctx.set_mode(mode);
+        for _ in 0..10 {
+            let mut input = random_grid(&mut rng, size, true);
+            let eye = Coord::new(rng.below(31) as i32, rng.below(25) as i32);
+            let mut fov = Fov::new();
+            let mut expected = Fov::new();
+            ctx.observe_fov(
+                &mut fov,
+                eye,
+                &TestInputGrid,
+                &input,
+                vision_distance,
+                255,
+                |_, _| (),
+            );
+            for _ in 0..40 {
+                let before = fov_grid(&fov, size);
+                let changed = Coord::new(rng.below(31) as i32, rng.below(25) as i32);
+                *input.get_mut(changed).unwrap() = [0, 100, 255][rng.below(3) as usize];
+                let mut changes = Grid::new_fn(size, |_| None);
+                ctx.update_fov_cell(
+                    &mut fov,
+                    changed,
+                    &TestInputGrid,
+                    &input,
+                    vision_distance,
+                    255,
+                    |coord, change| {
+                        let cell = changes.get_mut(coord).unwrap();
+                        assert!(cell.is_none(), "already have change at {:?}", coord);
+                        *cell = Some(change);
+                    },
+                );
+                ctx.observe_fov(
+                    &mut expected,
+                    eye,
+                    &TestInputGrid,
+                    &input,
+                    vision_distance,
+                    255,
+                    |_, _| (),
+                );
+                let after = fov_grid(&expected, size);
+                assert_eq!(
+                    fov_grid(&fov, size).cells,
+                    after.cells,
+                    "{:?} changing {:?}",
+                    eye,
+                    changed
+                );
+                for coord in size.coord_iter_row_major() {
+                    let change =
+                        match (*before.get(coord).unwrap(), *after.get(coord).unwrap()) {
+                            (None, Some((bitmap, visibility))) => {
+                                Some(FovChange::Revealed(bitmap, visibility))
+                            }
+                            (Some(_), None) => Some(FovChange::Hidden),
+                            (Some(a), Some((bitmap, visibility)))
+                                if a != (bitmap, visibility) =>
+                            {
+                                Some(FovChange::Changed(bitmap, visibility))
+                            }
+                            _ => None,
+                        };
+                    assert_eq!(*changes.get(coord).unwrap(), change, "at {:?}", coord);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn fov_update_cell_with_view() {
+    // views with a cone or a falloff are updated incrementally, and other views by
+    // observing everything again
+    let mut rng = Rng(0xd00c_7e4a);
+    let size = Size::new(31, 25);
+    let vision_distance = vision_distance::Circle::new(12);
+    let mut ctx: Context<u8> = Context::default();
+    for mode in [Mode::Asymmetric, Mode::Symmetric] {
+        ctx.set_mode(mode);
+        for _ in 0..10 {
+            let mut input = random_grid(&mut rng, size, true);
+            let view = random_view(&mut rng, size);
+            let mut fov = Fov::new();
+            let mut expected = Fov::new();
+            ctx.observe_fov(
+                &mut fov,
+                view,
+                &TestInputGrid,
+                &input,
+                vision_distance,
+                255,
+                |_, _| (),
+            );
+            for _ in 0..40 {
+                let changed = Coord::new(rng.below(31) as i32, rng.below(25) as i32);
+                *input.get_mut(changed).unwrap() = [0, 100, 255][rng.below(3) as usize];
+                ctx.update_fov_cell(
+                    &mut fov,
+                    changed,
+                    &TestInputGrid,
+                    &input,
+                    vision_distance,
+                    255,
+                    |_, _| (),
+                );
+                ctx.observe_fov(
+                    &mut expected,
+                    view,
+                    &TestInputGrid,
+                    &input,
+                    vision_distance,
+                    255,
+                    |_, _| (),
+                );
+                assert_eq!(
+                    fov_grid(&fov, size).cells,
+                    fov_grid(&expected, size).cells,
+                    "{:?} changing {:?}",
+                    view,
+                    changed
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn fov_door() {
+    let (mut input, eye) = input_from_strs(&[
+        "#########",
+        "#...#...#",
+        "#.@.....#",
+        "#...#...#",
+        "#########",
+    ]);
+    let door = Coord::new(4, 2);
+    let vision_distance = vision_distance::Circle::new(10);
+    let mut ctx: Context<u8> = Context::default();
+    let mut fov = Fov::new();
+    ctx.observe_fov(
+        &mut fov,
+        eye,
+        &TestInputGrid,
+        &input,
+        vision_distance,
+        255,
+        |_, _| (),
+    );
+    *input.get_mut(door).unwrap() = 255;
+    let mut hidden = Vec::new();
+    ctx.update_fov_cell(
+        &mut fov,
+        door,
+        &TestInputGrid,
+        &input,
+        vision_distance,
+        255,
+        |coord, change| match change {
+            FovChange::Hidden => hidden.push(coord),
+            FovChange::Changed(_, _) => (),
+            FovChange::Revealed(_, _) => panic!("revealed {:?}", coord),
+        },
+    );
+    assert!(fov.get(door).is_some());
+    assert!(hidden.contains(&Coord::new(5, 2)));
+    assert!(hidden.iter().all(|coord| coord.x > 4));
+    let mut revealed = Vec::new();
+    *input.get_mut(door).unwrap() = 0;
+    ctx.update_fov_cell(
+        &mut fov,
+        door,
+        &TestInputGrid,
+        &input,
+        vision_distance,
+        255,
+        |coord, change| {
+            if let FovChange::Revealed(_, _) = change {
+                revealed.push(coord);
+            }
+        },
+    );
+    assert_eq!(revealed, hidden);
+}
+
+#[test]
+fn region_bounds_contain_affected_cells() {
+    let max_depth = 12;
+    for changed in Size::new(17, 17).coord_iter_row_major() {
+        let changed = changed - Coord::new(8, 8);
+        let bounds = region_bounds(changed, max_depth);
+        for delta in Size::new(25, 25).coord_iter_row_major() {
+            let delta = delta - Coord::new(max_depth, max_depth);
+            if region_effect(changed, delta) {
+                let (min, max) = bounds.unwrap();
+                assert!(
+                    delta.x >= min.x
+                        && delta.y >= min.y
+                        && delta.x <= max.x
+                        && delta.y <= max.y,
+                    "changing {:?} affects {:?}",
+                    changed,
+                    delta
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn fov_update_cell_lookups() {
+    let mut input = Grid::new_fn(Size::new(81, 81), |_| 0);
+    let eye = Coord::new(40, 40);
+    let vision_distance = vision_distance::Circle::new(30);
+    let input_grid = CountingInputGrid {
+        count: std::cell::Cell::new(0),
+    };
+    let mut ctx: Context<u8> = Context::default();
+    let mut fov = Fov::new();
+    ctx.observe_fov(
+        &mut fov,
+        eye,
+        &input_grid,
+        &input,
+        vision_distance,
+        255,
+        |_, _| (),
+    );
+    let observe_lookups = input_grid.count.replace(0);
+    let mut update_lookups = |changed: Coord| {
+        *input.get_mut(changed).unwrap() = 255;
+        ctx.update_fov_cell(
+            &mut fov,
+            changed,
+            &input_grid,
+            &input,
+            vision_distance,
+            255,
+            |_, _| (),
+        );
+        *input.get_mut(changed).unwrap() = 0;
+        ctx.update_fov_cell(
+            &mut fov,
+            changed,
+            &input_grid,
+            &input,
+            vision_distance,
+            255,
+            |_, _| (),
+        );
+        input_grid.count.replace(0) / 2
+    };
+    // only the wedge behind the changed cell is scanned again, which narrows as the
+    // changed cell moves away from the eye
+    let near = update_lookups(eye + Coord::new(5, 2));
+    let far = update_lookups(eye + Coord::new(20, 8));
+    assert!(
+        near < observe_lookups / 2,
+        "{} of {}",
+        near,
+        observe_lookups
+    );
+    assert!(far < near / 2, "{} of {}", far, near);
+}
+
+#[test]
+fn fov_moving_eye_matches_observe_fov() {
+    let mut rng = Rng(0x57e9);
+    let size = Size::new(31, 25);
+    let vision_distance = vision_distance::Circle::new(10);
+    let input = random_grid(&mut rng, size, true);
+    let mut ctx: Context<u8> = Context::default();
+    let mut fov = Fov::new();
+    let mut expected = Fov::new();
+    let mut changes = Grid::new_fn(size, |_| None);
+    ctx.observe_fov(
+        &mut fov,
+        Coord::new(15, 12),
+        &TestInputGrid,
+        &input,
+        vision_distance,
+        255,
+        |coord, change| {
+            *changes.get_mut(coord).unwrap() = Some(change);
+        },
+    );
+    assert_eq!(
+        fov_grid(&fov, size).cells,
+        changes
+            .cells
+            .iter()
+            .map(|change| match *change {
+                Some(FovChange::Revealed(bitmap, visibility)) =>
+                    Some((bitmap, visibility)),
+                None => None,
+                _ => panic!(),
+            })
+            .collect::<Vec<_>>()
+    );
+    for _ in 0..100 {
+        let eye = fov.eye().unwrap();
+        let direction = Direction::all().nth(rng.below(8) as usize).unwrap();
+        if !(eye + direction.coord()).is_valid(size) {
+            continue;
+        }
+        let before = fov_grid(&fov, size);
+        let mut changes = Grid::new_fn(size, |_| None);
+        ctx.observe_fov(
+            &mut fov,
+            eye + direction.coord(),
+            &TestInputGrid,
+            &input,
+            vision_distance,
+            255,
+            |coord, change| {
+                let cell = changes.get_mut(coord).unwrap();
+                assert!(cell.is_none(), "already have change at {:?}", coord);
+                *cell = Some(change);
+            },
+        );
+        assert_eq!(fov.eye(), Some(eye + direction.coord()));
+        ctx.observe_fov(
+            &mut expected,
+            eye + direction.coord(),
+            &TestInputGrid,
+            &input,
+            vision_distance,
+            255,
+            |_, _| (),
+        );
+        let after = fov_grid(&expected, size);
+        assert_eq!(fov_grid(&fov, size).cells, after.cells);
+        for coord in size.coord_iter_row_major() {
+            match (
+                *before.get(coord).unwrap(),
+                *after.get(coord).unwrap(),
+                *changes.get(coord).unwrap(),
+            ) {
+                (None, None, None) => (),
+                (None, Some(a), Some(FovChange::Revealed(bitmap, visibility))) => {
+                    assert_eq!(a, (bitmap, visibility))
+                }
+                (Some(_), None, Some(FovChange::Hidden)) => (),
+                (Some(b), Some(a), Some(FovChange::Changed(bitmap, visibility))) => {
+                    assert_ne!(b, a);
+                    assert_eq!(a, (bitmap, visibility));
+                }
+                (Some(b), Some(a), None) => assert_eq!(b, a),
+                other => panic!("unexpected change at {:?}: {:?}", coord, other),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn par_for_each_visible_matches_for_each_visible() {
+    let mut rng = Rng(0x9a7a);
+    let size = Size::new(40, 30);
+    let vision_distance = vision_distance::Circle::new(16);
+    for (i, mode) in [Mode::Asymmetric, Mode::Symmetric, Mode::Permissive(8)]
+        .into_iter()
+        .cycle()
+        .take(15)
+        .enumerate()
+    {
+        let mut ctx: Context<u8> = Context::new(mode);
+        let input = random_grid(&mut rng, size, true);
+        let eye = Coord::new(rng.below(40) as i32, rng.below(30) as i32);
+        let wrap = [Wrap::None, Wrap::Both][i % 2];
+        let mut expected = Vec::new();
+        ctx.for_each_visible(
+            eye,
+            &WrapInputGrid(wrap),
+            &input,
+            vision_distance,
+            255,
+            |coord, bitmap, visibility| expected.push((coord, bitmap, visibility)),
+        );
+        let mut output = Vec::new();
+        ctx.par_for_each_visible(
+            eye,
+            &WrapInputGrid(wrap),
+            &input,
+            vision_distance,
+            255,
+            |coord, bitmap, visibility| output.push((coord, bitmap, visibility)),
+        );
+        assert_eq!(output, expected);
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn context_pool_matches_for_each_visible() {
+    use std::sync::Mutex;
+    let mut rng = Rng(0xba7c);
+    let size = Size::new(40, 30);
+    let input = random_grid(&mut rng, size, true);
+    let viewers = (0..50)
+        .map(|_| Viewer {
+            view: random_view(&mut rng, size),
+            vision_distance: vision_distance::Circle::new(2 + rng.below(12)),
+            initial_visibility: 100 + rng.below(156) as u8,
+        })
+        .collect::<Vec<_>>();
+    let mut ctx: Context<u8> = Context::new(Mode::Symmetric);
+    let expected = viewers
+        .iter()
+        .map(|viewer| {
+            let mut visible = Vec::new();
+            ctx.for_each_visible(
+                viewer.view,
+                &TestInputGrid,
+                &input,
+                viewer.vision_distance,
+                viewer.initial_visibility,
+                |coord, bitmap, visibility| visible.push((coord, bitmap, visibility)),
+            );
+            visible
+        })
+        .collect::<Vec<_>>();
+    let pool = ContextPool::new(Mode::Symmetric);
+    assert_eq!(pool.visible(&viewers, &TestInputGrid, &input), expected);
+    let output = viewers
+        .iter()
+        .map(|_| Mutex::new(Vec::new()))
+        .collect::<Vec<_>>();
+    pool.for_each_visible(&viewers, &TestInputGrid, &input, |index| {
+        let mut visible = output[index].lock().unwrap();
+        move |coord, bitmap, visibility| visible.push((coord, bitmap, visibility))
+    });
+    let output = output
+        .into_iter()
+        .map(|visible| visible.into_inner().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn visible_iter_matches_for_each_visible() {
+    let mut rng = Rng(0x17e4);
+    let size = Size::new(30, 20);
+    let vision_distance = vision_distance::Circle::new(12);
+    for (i, mode) in [Mode::Asymmetric, Mode::Symmetric, Mode::Permissive(8)]
+        .into_iter()
+        .cycle()
+        .take(15)
+        .enumerate()
+    {
+        let mut ctx: Context<u8> = Context::new(mode);
+        let input = random_grid(&mut rng, size, true);
+        let eye = Coord::new(rng.below(30) as i32, rng.below(20) as i32);
+        let wrap = [Wrap::None, Wrap::Horizontal][i % 2];
+        let mut expected = Vec::new();
+        ctx.for_each_visible(
+            eye,
+            &WrapInputGrid(wrap),
+            &input,
+            vision_distance,
+            255,
+            |coord, bitmap, visibility| expected.push((coord, bitmap, visibility)),
+        );
+        let output = ctx
+            .visible_iter(eye, &WrapInputGrid(wrap), &input, vision_distance, 255)
+            .collect::<Vec<_>>();
+        assert_eq!(output, expected);
+    }
+}
+
+#[test]
+fn visible_iter_stops_early() {
+    let size = Size::new(41, 41);
+    let input = Grid::new_fn(size, |_| 0);
+    let eye = Coord::new(20, 20);
+    let input_grid = CountingInputGrid {
+        count: std::cell::Cell::new(0),
+    };
+    let mut ctx: Context<u8> = Context::default();
+    let near = ctx
+        .visible_iter(
+            eye,
+            &input_grid,
+            &input,
+            vision_distance::Circle::new(20),
+            255,
+        )
+        .find(|&(coord, _, _)| coord == eye + Coord::new(-2, -3));
+    assert!(near.is_some());
+    let partial = input_grid.count.replace(0);
+    ctx.for_each_visible(
+        eye,
+        &input_grid,
+        &input,
+        vision_distance::Circle::new(20),
+        255,
+        |_, _, _| (),
+    );
+    assert!(partial * 10 < input_grid.count.get());
+    // abandoning the iterator leaves the context ready to be used again
+    let visible = |ctx: &mut Context<u8>| {
+        let mut visible = Vec::new();
+        ctx.for_each_visible(
+            eye,
+            &TestInputGrid,
+            &input,
+            vision_distance::Circle::new(20),
+            255,
+            |coord, bitmap, visibility| visible.push((coord, bitmap, visibility)),
+        );
+        visible
+    };
+    let _ = ctx
+        .visible_iter(
+            eye,
+            &TestInputGrid,
+            &input,
+            vision_distance::Circle::new(20),
+            255,
+        )
+        .nth(100);
+    assert_eq!(visible(&mut ctx), visible(&mut Context::default()));
+}
+
+#[test]
+fn try_for_each_visible_stops_early() {
+    let size = Size::new(41, 41);
+    let (input, eye) = input_from_strs(&[
+        ".........",
+        ".........",
+        "....#....",
+        "....@....",
+        ".........",
+    ]);
+    let mut ctx: Context<u8> = Context::default();
+    let mut count = 0;
+    let flow = ctx.try_for_each_visible(
+        eye,
+        &TestInputGrid,
+        &input,
+        vision_distance::Circle::new(10),
+        255,
+        |coord, _, _| {
+            count += 1;
+            if *input.get(coord).unwrap() == 255 {
+                ControlFlow::Break(coord)
+            } else {
+                ControlFlow::Continue(())
+            }
+        },
+    );
+    assert_eq!(flow, ControlFlow::Break(Coord::new(4, 2)));
+    assert!(count < 45);
+
+    let input = Grid::new_fn(size, |_| 0);
+    let eye = Coord::new(20, 20);
+    let input_grid = CountingInputGrid {
+        count: std::cell::Cell::new(0),
+    };
+    let flow = ctx.try_for_each_visible(
+        eye,
+        &input_grid,
+        &input,
+        vision_distance::Circle::new(20),
+        255,
+        |coord, _, _| {
+            if coord == eye + Coord::new(-2, -3) {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        },
+    );
+    assert!(flow.is_break());
+    let partial = input_grid.count.replace(0);
+    let mut expected = Vec::new();
+    let flow: ControlFlow<()> = ctx.try_for_each_visible(
+        eye,
+        &input_grid,
+        &input,
+        vision_distance::Circle::new(20),
+        255,
+        |coord, bitmap, visibility| {
+            expected.push((coord, bitmap, visibility));
+            ControlFlow::Continue(())
+        },
+    );
+    assert!(flow.is_continue());
+    assert!(partial * 10 < input_grid.count.get());
+    let mut output = Vec::new();
+    ctx.for_each_visible(
+        eye,
+        &input_grid,
+        &input,
+        vision_distance::Circle::new(20),
+        255,
+        |coord, bitmap, visibility| output.push((coord, bitmap, visibility)),
+    );
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn falloff_matches_for_each_visible() {
+    let mut rng = Rng(0x0f0f_1234);
+    let size = Size::new(24, 17);
+    let vision_distance = vision_distance::Circle::new(10);
+    let linear = falloff::Linear::new(7.5);
+    let custom =
+        falloff::Custom::new(|distance: f64| if distance < 4.0 { 1.0 } else { 0.5 });
+    for i in 0..20 {
+        let mut ctx: Context<u8> = Context::new(if i % 2 == 0 {
+            Mode::Asymmetric
+        } else {
+            Mode::Symmetric
+        });
+        let input = random_grid(&mut rng, size, true);
+        let eye = Coord::new(rng.below(24) as i32, rng.below(17) as i32);
+        let visibility = visibility_grid(&mut ctx, &input, eye, vision_distance);
+        let check = |output: Grid<Option<u8>>, factor: &dyn Fn(f64) -> f64| {
+            for coord in size.coord_iter_row_major() {
+                let delta = coord - eye;
+                let distance = (delta.x as f64).hypot(delta.y as f64);
+                let expected = visibility
+                    .get(coord)
+                    .unwrap()
+                    .map(|visibility| (visibility as f64 * factor(distance)) as u8)
+                    .filter(|&visibility| visibility > 0);
+                assert_eq!(*output.get(coord).unwrap(), expected, "{:?}", coord);
+            }
+        };
+        let mut output = Grid::new_fn(size, |_| None);
+        ctx.for_each_visible(
+            View::new(eye).falloff(linear),
+            &TestInputGrid,
+            &input,
+            vision_distance,
+            255,
+            |coord, _, visibility| {
+                let cell = output.get_mut(coord).unwrap();
+                *cell = cmp::max(*cell, Some(visibility));
+            },
+        );
+        check(output, &|distance| linear.factor(distance).max(0.0));
+        let mut output = Grid::new_fn(size, |_| None);
+        ctx.for_each_visible(
+            View::new(eye).falloff(custom),
+            &TestInputGrid,
+            &input,
+            vision_distance,
+            255,
+            |coord, _, visibility| {
+                let cell = output.get_mut(coord).unwrap();
+                *cell = cmp::max(*cell, Some(visibility));
+            },
+        );
+        check(output, &|distance| custom.factor(distance));
+    }
+}
+
+#[test]
+fn scan_stops_where_falloff_reaches_zero() {
+    let input = Grid::new_fn(Size::new(201, 201), |_| 0);
+    let input_grid = CountingInputGrid {
+        count: std::cell::Cell::new(0),
+    };
+    let mut ctx: Context<u8> = Context::default();
+    let vision_distance = UnboundedCircle(vision_distance::Circle::new(100));
+    let mut visible = 0;
+    ctx.for_each_visible(
+        View::new(Coord::new(100, 100)).falloff(falloff::Linear::new(5.0)),
+        &input_grid,
+        &input,
+        vision_distance,
+        255,
+        |_, _, _| visible += 1,
+    );
+    // cells along the axes and diagonals are scanned by two octants
+    let side = 11;
+    assert!(visible <= side * side);
+    assert!(input_grid.count.replace(0) <= 2 * side * side);
+    // 255 / (1 + (d / 2)^2) rounds down to zero beyond a distance of about 32
+    let mut furthest = 0;
+    ctx.for_each_visible(
+        View::new(Coord::new(100, 100)).falloff(falloff::InverseSquare::new(2.0)),
+        &input_grid,
+        &input,
+        vision_distance,
+        255,
+        |coord, _, _| {
+            let delta = coord - Coord::new(100, 100);
+            furthest = cmp::max(furthest, cmp::max(delta.x.abs(), delta.y.abs()));
+        },
+    );
+    assert_eq!(furthest, 31);
+    let side = 2 * 32 + 1;
+    assert!(input_grid.count.get() <= 2 * side * side);
+}
+
+struct WallInputGrid;
+
+struct Walled {
+    cells: Grid<u8>,
+    walls: HashMap<(Coord, CellEdge), u8>,
+}
+
+impl InputGrid for WallInputGrid {
+    type Grid = Walled;
+    type Opacity = u8;
+    fn size(&self, grid: &Self::Grid) -> Size {
+        grid.cells.size
+    }
+    fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity {
+        *grid.cells.get(coord).unwrap()
+    }
+    fn get_edge_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        edge: CellEdge,
+    ) -> Option<Self::Opacity> {
+        grid.walls.get(&(coord, edge)).cloned()
+    }
+}
+
+fn walled_output(
+    eye: Coord,
+    walled: &Walled,
+) -> (TestOutputGrid, Vec<(Coord, CellEdge, u8)>) {
+    let mut output = Grid::new_fn(walled.cells.size, |_| None);
+    let mut ctx: Context<u8> = Context::default();
+    ctx.for_each_visible(
+        View::new(eye).walls(),
+        &WallInputGrid,
+        walled,
+        vision_distance::Circle::new(100),
+        255,
+        |coord, bitmap, _| {
+            let cell = output.get_mut(coord).unwrap();
+            assert!(cell.is_none(), "already have value at {:?}", coord);
+            *cell = Some(bitmap);
+        },
+    );
+    let walls = ctx.visible_walls().to_vec();
+    #[cfg(feature = "rayon")]
+    {
+        ctx.par_for_each_visible(
+            View::new(eye).walls(),
+            &WallInputGrid,
+            walled,
+            vision_distance::Circle::new(100),
+            255,
+            |_, _, _| (),
+        );
+        assert_eq!(ctx.visible_walls(), walls);
+    }
+    (output, walls)
+}
+
+#[test]
+fn thin_walls() {
+    let (cells, eye) = input_from_strs(&[
+        ".............",
+        ".............",
+        ".............",
+        ".............",
+        ".............",
+        "......@......",
+        ".............",
+    ]);
+    let mut walls = HashMap::new();
+    // a wall with a doorway, and a wall beside the eye
+    for x in (0..6).chain(8..13) {
+        walls.insert((Coord::new(x, 4), CellEdge::North), 255);
+    }
+    walls.insert((Coord::new(9, 5), CellEdge::West), 255);
+    let (output, visible_walls) = walled_output(eye, &Walled { cells, walls });
+    check_output(
+        eye,
+        &output,
+        &[
+            "%%%%,,,,,,,,%",
+            "%%%%%,,,,,,%%",
+            "%%%%%,,,,,%%%",
+            "%%%%%,,,,%%%%",
+            ",,,,,,,,,%%%%",
+            ",,,,,,@,,%%%%",
+            ",,,,,,,,,%%%%",
+        ],
+    );
+    let mut expected = (0..6)
+        .chain(8..10)
+        .map(|x| (Coord::new(x, 4), CellEdge::North, 255))
+        .collect::<Vec<_>>();
+    expected.push((Coord::new(9, 5), CellEdge::West, 255));
+    assert_eq!(visible_walls, expected);
+}
+
+#[test]
+fn thin_wall_room() {
+    let (cells, eye) = input_from_strs(&[
+        ".........",
+        ".........",
+        "....@....",
+        ".........",
+        ".........",
+    ]);
+    let mut walls = HashMap::new();
+    for x in 2..7 {
+        walls.insert((Coord::new(x, 1), CellEdge::North), 255);
+        walls.insert((Coord::new(x, 4), CellEdge::North), 255);
+    }
+    for y in 1..4 {
+        walls.insert((Coord::new(2, y), CellEdge::West), 255);
+        walls.insert((Coord::new(7, y), CellEdge::West), 255);
+    }
+    let num_walls = walls.len();
+    let (output, visible_walls) = walled_output(eye, &Walled { cells, walls });
+    check_output(
+        eye,
+        &output,
+        &[
+            "%%%%%%%%%",
+            "%%,,,,,%%",
+            "%%,,@,,%%",
+            "%%,,,,,%%",
+            "%%%%%%%%%",
+        ],
+    );
+    assert_eq!(visible_walls.len(), num_walls);
+}
+
+#[test]
+fn thin_walls_match_is_visible() {
+    let mut rng = Rng(0x7a11_5eed);
+    let size = Size::new(20, 15);
+    let vision_distance = vision_distance::Circle::new(9);
+    for i in 0..30 {
+        let mut ctx: Context<u8> = Context::new(match i % 3 {
+            0 => Mode::Asymmetric,
+            1 => Mode::Symmetric,
+            _ => Mode::Permissive(8),
+        });
+        let cells = random_grid(&mut rng, size, true);
+        let mut walls = HashMap::new();
+        for coord in size.coord_iter_row_major() {
+            for edge in [CellEdge::North, CellEdge::West] {
+                match rng.below(8) {
+                    0 => walls.insert((coord, edge), 255),
+                    1 => walls.insert((coord, edge), 100),
+                    _ => None,
+                };
+            }
+        }
+        let walled = Walled { cells, walls };
+        let eye = Coord::new(rng.below(20) as i32, rng.below(15) as i32);
+        let mut with_walls = Grid::new_fn(size, |_| None);
+        ctx.for_each_visible(
+            eye,
+            &WallInputGrid,
+            &walled,
+            vision_distance,
+            255,
+            |coord, _, visibility| {
+                let cell = with_walls.get_mut(coord).unwrap();
+                *cell = cmp::max(*cell, Some(visibility));
+            },
+        );
+        for to in size.coord_iter_row_major() {
+            let visibility =
+                ctx.is_visible(eye, to, &WallInputGrid, &walled, vision_distance, 255);
+            assert_eq!(visibility, *with_walls.get(to).unwrap(), "to {:?}", to);
+            // a cell enclosed by walls can't be seen from outside
+            let enclosed = [
+                (to, CellEdge::North),
+                (to, CellEdge::West),
+                (to + Coord::new(0, 1), CellEdge::North),
+                (to + Coord::new(1, 0), CellEdge::West),
+            ]
+            .iter()
+            .all(|wall| walled.walls.get(wall) == Some(&255));
+            if enclosed && to != eye {
+                assert_eq!(visibility, None, "to {:?}", to);
+            }
+        }
+    }
+}
+
+#[test]
+fn view_combines_options() {
+    let (cells, eye) = input_from_strs(&[
+        ".............",
+        ".............",
+        ".............",
+        ".............",
+        ".............",
+        "......@......",
+        ".............",
+    ]);
+    let mut walls = HashMap::new();
+    for x in (0..6).chain(8..13) {
+        walls.insert((Coord::new(x, 4), CellEdge::North), 255);
+    }
+    walls.insert((Coord::new(9, 5), CellEdge::West), 255);
+    let walled = Walled { cells, walls };
+    let cone = Cone::from_direction(Direction::North, std::f64::consts::FRAC_PI_4);
+    let linear = falloff::Linear::new(4.0);
+    let vision_distance = vision_distance::Circle::new(100);
+    let mut ctx: Context<u8> = Context::default();
+    let mut in_cone = Vec::new();
+    ctx.for_each_visible(
+        View::new(eye).cone(cone),
+        &WallInputGrid,
+        &walled,
+        vision_distance,
+        255,
+        |coord, _, visibility| {
+            let delta = coord - eye;
+            let distance = (delta.x as f64).hypot(delta.y as f64);
+            if let Some(visibility) = linear.apply(visibility, distance) {
+                in_cone.push((coord, visibility));
+            }
+        },
+    );
+    let (_, all_walls) = walled_output(eye, &walled);
+    let mut visible = Vec::new();
+    ctx.for_each_visible(
+        View::new(eye).cone(cone).falloff(linear).walls(),
+        &WallInputGrid,
+        &walled,
+        vision_distance,
+        255,
+        |coord, _, visibility| visible.push((coord, visibility)),
+    );
+    assert_eq!(visible, in_cone);
+    // only the walls in front of the eye which are within the range of the falloff
+    let visible_walls = ctx.visible_walls();
+    assert!(!visible_walls.is_empty());
+    for &(coord, edge, visibility) in visible_walls {
+        assert_eq!(edge, CellEdge::North);
+        assert!((coord.x - eye.x).abs() <= 2, "{:?}", coord);
+        assert!(all_walls.contains(&(coord, edge, 255)));
+        assert!(visibility < 255);
+    }
+}
+
+/// A view from a random cell of a grid of the given size, with a random combination of
+/// options
+fn random_view(rng: &mut Rng, size: Size) -> View<falloff::Linear> {
+    let coord = Coord::new(
+        rng.below(size.width()) as i32,
+        rng.below(size.height()) as i32,
+    );
+    let mut view =
+        View::new(coord).falloff(falloff::Linear::new(8.0 + rng.below(40) as f64));
+    if rng.below(2) == 0 {
+        let facing = rng.below(6283) as f64 / 1000.0 - std::f64::consts::PI;
+        view = view.cone(Cone::new(facing, 0.2 + rng.below(2800) as f64 / 1000.0));
+    }
+    if rng.below(3) == 0 {
+        let mut offset = || rng.below(15) as i32 - 7;
+        view = view.eye_offset(Coord::new(offset(), offset()));
+    }
+    if rng.below(3) == 0 {
+        view = view.footprint(Size::new(1 + rng.below(3), 1 + rng.below(3)));
+    }
+    view
+}
+
+#[test]
+fn views_apply_to_every_way_of_observing() {
+    let mut rng = Rng(0x7e_4a11);
+    let size = Size::new(20, 15);
+    let vision_distance = vision_distance::Circle::new(10);
+    for i in 0..30 {
+        let mut ctx: Context<u8> = Context::new(match i % 3 {
+            0 => Mode::Asymmetric,
+            1 => Mode::Symmetric,
+            _ => Mode::Permissive(8),
+        });
+        let input = random_grid(&mut rng, size, true);
+        let view = random_view(&mut rng, size);
+        let mut expected = Vec::new();
+        ctx.for_each_visible(
+            view,
+            &TestInputGrid,
+            &input,
+            vision_distance,
+            255,
+            |coord, bitmap, visibility| expected.push((coord, bitmap, visibility)),
+        );
+        let mut output = Vec::new();
+        let _: ControlFlow<()> = ctx.try_for_each_visible(
+            view,
+            &TestInputGrid,
+            &input,
+            vision_distance,
+            255,
+            |coord, bitmap, visibility| {
+                output.push((coord, bitmap, visibility));
+                ControlFlow::Continue(())
+            },
+        );
+        assert_eq!(output, expected, "{:?}", view);
+        let output = ctx
+            .visible_iter(view, &TestInputGrid, &input, vision_distance, 255)
+            .collect::<Vec<_>>();
+        assert_eq!(output, expected, "{:?}", view);
+        #[cfg(feature = "rayon")]
+        {
+            let mut output = Vec::new();
+            ctx.par_for_each_visible(
+                view,
+                &TestInputGrid,
+                &input,
+                vision_distance,
+                255,
+                |coord, bitmap, visibility| output.push((coord, bitmap, visibility)),
+            );
+            assert_eq!(output, expected, "{:?}", view);
+        }
+        let mut expected_grid = Grid::new_fn(size, |_| None);
+        for &(coord, _, visibility) in &expected {
+            *expected_grid.get_mut(coord).unwrap() = Some(visibility);
+        }
+        for to in size.coord_iter_row_major() {
+            let visibility =
+                ctx.is_visible(view, to, &TestInputGrid, &input, vision_distance, 255);
+            assert_eq!(
+                visibility,
+                *expected_grid.get(to).unwrap(),
+                "{:?} to {:?}",
+                view,
+                to
+            );
+        }
+    }
+}
+
+/// Cells which can be seen through when looking south, but not when looking north
+struct OneWayInputGrid;
+
+impl InputGrid for OneWayInputGrid {
+    type Grid = Grid<bool>;
+    type Opacity = u8;
+    fn size(&self, grid: &Self::Grid) -> Size {
+        grid.size
+    }
+    fn get_opacity(&self, _grid: &Self::Grid, _coord: Coord) -> Self::Opacity {
+        0
+    }
+    fn get_directional_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        direction: CardinalDirection,
+    ) -> Self::Opacity {
+        if *grid.get(coord).unwrap() && direction == CardinalDirection::North {
+            255
+        } else {
+            0
+        }
+    }
+}
+
+#[test]
+fn one_way_opacity() {
+    let size = Size::new(7, 11);
+    let mirrors = Grid::new_fn(size, |coord| coord.y == 5);
+    let mut ctx: Context<u8> = Context::default();
+    let visible = |ctx: &mut Context<u8>, eye| {
+        let mut output = Grid::new_fn(size, |_| false);
+        ctx.for_each_visible(
+            eye,
+            &OneWayInputGrid,
+            &mirrors,
+            vision_distance::Circle::new(100),
+            255,
+            |coord, _, _| *output.get_mut(coord).unwrap() = true,
+        );
+        output
+    };
+    // looking north, the mirrors are seen but not what's behind them
+    let output = visible(&mut ctx, Coord::new(3, 8));
+    for coord in size.coord_iter_row_major() {
+        assert_eq!(*output.get(coord).unwrap(), coord.y >= 5, "{:?}", coord);
+    }
+    // looking south, everything is seen through the mirrors
+    let output = visible(&mut ctx, Coord::new(3, 2));
+    assert!(output.cells.iter().all(|&visible| visible));
+}
+
+/// Doors which can be seen through only by members of the faction they belong to
+struct FactionInputGrid;
+
+impl InputGrid for FactionInputGrid {
+    type Grid = Grid<u8>;
+    type Opacity = u8;
+    fn size(&self, grid: &Self::Grid) -> Size {
+        grid.size
+    }
+    fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity {
+        match *grid.get(coord).unwrap() {
+            0 => 0,
+            _ => 255,
+        }
+    }
+}
+
+impl ViewerInputGrid<u8> for FactionInputGrid {
+    fn get_viewer_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        faction: &u8,
+    ) -> Self::Opacity {
+        match *grid.get(coord).unwrap() {
+            door if door == *faction => 0,
+            _ => self.get_opacity(grid, coord),
+        }
+    }
+}
+
+#[test]
+fn viewer_opacity() {
+    // a room with a door belonging to faction 1 in its north wall
+    let size = Size::new(9, 6);
+    let grid = Grid::new_fn(size, |coord| match (coord.x, coord.y) {
+        (4, 2) => 1,
+        (_, 2) => 255,
+        _ => 0,
+    });
+    let mut ctx: Context<u8> = Context::default();
+    let mut visible = |faction: u8| {
+        let mut visible = Vec::new();
+        ctx.for_each_visible(
+            Coord::new(4, 0),
+            &ForViewer::new(&FactionInputGrid, &faction),
+            &grid,
+            vision_distance::Circle::new(100),
+            255,
+            |coord, _, _| visible.push(coord),
+        );
+        visible
+    };
+    let seen_with_door = |door: u8| {
+        let grid = Grid::new_fn(size, |coord| match *grid.get(coord).unwrap() {
+            1 => door,
+            opacity => opacity,
+        });
+        let mut visible = Vec::new();
+        Context::default().for_each_visible(
+            Coord::new(4, 0),
+            &TestInputGrid,
+            &grid,
+            vision_distance::Circle::new(100),
+            255,
+            |coord, _, _| visible.push(coord),
+        );
+        visible
+    };
+    let (open, closed) = (seen_with_door(0), seen_with_door(255));
+    assert!(open.iter().any(|coord| coord.y > 2));
+    assert!(closed.iter().all(|coord| coord.y <= 2));
+    assert_eq!(visible(1), open);
+    assert_eq!(visible(2), closed);
+}
+
+/// Thin walls with an opacity of 1 are doors which only faction 1 can see through
+struct FactionWallInputGrid;
+
+impl InputGrid for FactionWallInputGrid {
+    type Grid = Walled;
+    type Opacity = u8;
+    fn size(&self, grid: &Self::Grid) -> Size {
+        WallInputGrid.size(grid)
+    }
+    fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity {
+        WallInputGrid.get_opacity(grid, coord)
+    }
+    fn get_edge_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        edge: CellEdge,
+    ) -> Option<Self::Opacity> {
+        WallInputGrid
+            .get_edge_opacity(grid, coord, edge)
+            .map(|opacity| if opacity == 1 { 255 } else { opacity })
+    }
+}
+
+impl ViewerInputGrid<u8> for FactionWallInputGrid {
+    fn get_viewer_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        _faction: &u8,
+    ) -> Self::Opacity {
+        self.get_opacity(grid, coord)
+    }
+    fn get_edge_viewer_opacity(
+        &self,
+        grid: &Self::Grid,
+        coord: Coord,
+        edge: CellEdge,
+        faction: &u8,
+    ) -> Option<Self::Opacity> {
+        match WallInputGrid.get_edge_opacity(grid, coord, edge) {
+            Some(1) if *faction == 1 => Some(0),
+            _ => self.get_edge_opacity(grid, coord, edge),
+        }
+    }
+}
+
+fn visible_in_walled<I>(eye: Coord, input_grid: &I, walled: &Walled) -> Vec<Coord>
+where
+    I: InputGrid<Grid = Walled, Opacity = u8>,
+{
+    let mut visible = Vec::new();
+    Context::default().for_each_visible(
+        eye,
+        input_grid,
+        walled,
+        vision_distance::Circle::new(100),
+        255,
+        |coord, _, _| visible.push(coord),
+    );
+    visible
+}
+
+#[test]
+fn viewer_edge_opacity() {
+    // a thin wall with a door belonging to faction 1 in it
+    let walled = |door: u8| {
+        let (cells, _) = input_from_strs(&[".........", ".........", "....@...."]);
+        let mut walls = HashMap::new();
+        for x in 0..9 {
+            walls.insert((Coord::new(x, 2), CellEdge::North), 255);
+        }
+        walls.insert((Coord::new(4, 2), CellEdge::North), door);
+        Walled { cells, walls }
+    };
+    let eye = Coord::new(4, 2);
+    let open = visible_in_walled(eye, &WallInputGrid, &walled(0));
+    let closed = visible_in_walled(eye, &WallInputGrid, &walled(255));
+    assert!(open.iter().any(|coord| coord.y < 2));
+    assert!(closed.iter().all(|coord| coord.y == 2));
+    let for_faction = |faction: u8| {
+        visible_in_walled(
+            eye,
+            &ForViewer::new(&FactionWallInputGrid, &faction),
+            &walled(1),
+        )
+    };
+    assert_eq!(for_faction(1), open);
+    assert_eq!(for_faction(2), closed);
+}
+
+struct MultiplicativeInputGrid;
+
+impl InputGrid for MultiplicativeInputGrid {
+    type Grid = Grid<attenuation::Multiplicative<f32>>;
+    type Opacity = attenuation::Multiplicative<f32>;
+    fn size(&self, grid: &Self::Grid) -> Size {
+        grid.size
+    }
+    fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity {
+        *grid.get(coord).unwrap()
+    }
+}
+
+#[test]
+fn multiplicative_attenuation() {
+    // a corridor of fog which lets through half of the visibility of each cell, with a
+    // wall part way along
+    let size = Size::new(1, 12);
+    let grid = Grid::new_fn(size, |coord| {
+        attenuation::Multiplicative::new(if coord.y == 9 { 0.0 } else { 0.5 })
+    });
+    let mut visible = Vec::new();
+    Context::default().for_each_visible(
+        Coord::new(0, 0),
+        &MultiplicativeInputGrid,
+        &grid,
+        vision_distance::Circle::new(100),
+        1.0_f32,
+        |coord, _, visibility| visible.push((coord.y, visibility)),
+    );
+    visible.sort_by_key(|&(y, _)| y);
+    let expected = (0..10)
+        .map(|y| (y, 0.5_f32.powi(cmp::max(y - 1, 0))))
+        .collect::<Vec<_>>();
+    assert_eq!(visible, expected);
+}
+
+struct ThresholdInputGrid;
+
+impl InputGrid for ThresholdInputGrid {
+    type Grid = Grid<u8>;
+    type Opacity = attenuation::Threshold<u8>;
+    fn size(&self, grid: &Self::Grid) -> Size {
+        grid.size
+    }
+    fn get_opacity(&self, grid: &Self::Grid, coord: Coord) -> Self::Opacity {
+        attenuation::Threshold::new(*grid.get(coord).unwrap())
+    }
+}
+
+#[test]
+fn threshold_attenuation() {
+    // cells less opaque than the visibility are transparent, and the rest are opaque
+    let mut rng = Rng(0x7412_e540);
+    let size = Size::new(20, 15);
+    let vision_distance = vision_distance::Circle::new(9);
+    let mut ctx = Context::default();
+    for _ in 0..20 {
+        let grid = Grid::new_fn(size, |_| rng.below(256) as u8);
+        let eye = Coord::new(rng.below(20) as i32, rng.below(15) as i32);
+        let visibility = rng.below(256) as u8;
+        let mut output = Grid::new_fn(size, |_| None);
+        ctx.for_each_visible(
+            eye,
+            &ThresholdInputGrid,
+            &grid,
+            vision_distance,
+            visibility,
+            |coord, _, visibility| *output.get_mut(coord).unwrap() = Some(visibility),
+        );
+        let binary = Grid::new_fn(size, |coord| {
+            if *grid.get(coord).unwrap() < visibility {
+                0
+            } else {
+                255
+            }
+        });
+        let expected = visibility_grid(&mut ctx, &binary, eye, vision_distance);
+        for coord in size.coord_iter_row_major() {
+            let expected = expected.get(coord).unwrap().map(|_| visibility);
+            assert_eq!(*output.get(coord).unwrap(), expected, "{:?}", coord);
+        }
+    }
+}